@@ -0,0 +1,427 @@
+// See license info in LICENSE file
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post, put},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{decode_card, load_entry, store, telemetry, AppState, CacheEntry, Card, Config};
+
+/// Where assets get uploaded, which has its own body limit
+pub const ASSET_PATH: &str = "/_/asset";
+
+/// Counters shared between the request handlers
+#[derive(Default)]
+pub struct Stats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    /// Per path counts, for paths with something stored. Only kept when
+    /// `track_path_stats` is on
+    pub paths: Mutex<HashMap<String, PathStats>>,
+}
+
+#[derive(Default, Clone, Copy, Serialize)]
+pub struct PathStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/_/stats", get(handle_stats))
+        .route("/_/purge", post(handle_purge))
+        .route("/_/inspect", get(handle_inspect))
+        .route("/_/analytics", get(handle_analytics))
+        .route("/_/card", put(handle_put_card))
+        // limited by `max_upload_bytes` instead
+        .route(ASSET_PATH, put(handle_put_asset).layer(DefaultBodyLimit::disable()))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+async fn require_admin(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let config = state.config.load();
+    // as if they weren't there when nothing to authenticate with is set
+    if !config.admin_enabled() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if config.admin_open {
+        return next.run(request).await;
+    }
+    // a key that's sent but wrong is refused outright rather than asked for
+    if let Some(provided) = request.headers().get("X-API-Key") {
+        return match &config.api_key {
+            Some(key) if constant_time_eq(key.as_bytes(), provided.as_bytes()) => next.run(request).await,
+            _ => StatusCode::FORBIDDEN.into_response(),
+        };
+    }
+    let authorization = request.headers().get("Authorization").and_then(|v| v.to_str().ok());
+    let authorized = match authorization.and_then(|v| v.split_once(' ')) {
+        Some(("Bearer", provided)) => config
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), provided.as_bytes())),
+        Some(("Basic", provided)) => match (&config.admin_user, &config.admin_password, decode_basic(provided)) {
+            // both halves get compared so a wrong user takes as long as a
+            // wrong password
+            (Some(user), Some(password), Some((provided_user, provided_password))) => {
+                constant_time_eq(user.as_bytes(), provided_user.as_bytes())
+                    & constant_time_eq(password.as_bytes(), provided_password.as_bytes())
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+
+    if authorized {
+        next.run(request).await
+    } else if config.admin_user.is_some() {
+        // lets browsers prompt for the credentials
+        (StatusCode::UNAUTHORIZED, [("WWW-Authenticate", "Basic realm=\"site-shim\"")]).into_response()
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Splits basic auth credentials into the user and password
+fn decode_basic(credentials: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Compares two byte strings without short-circuiting on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    entry_count: u64,
+    weighted_size: u64,
+    hits: u64,
+    misses: u64,
+    /// Fraction of lookups served from the cache, or `None` before the first
+    /// lookup
+    hit_ratio: Option<f64>,
+}
+
+async fn handle_stats(State(state): State<AppState>) -> impl IntoResponse {
+    let hits = state.stats.hits.load(Ordering::Relaxed);
+    let misses = state.stats.misses.load(Ordering::Relaxed);
+    Json(StatsResponse {
+        entry_count: state.cache.entry_count(),
+        weighted_size: state.cache.weighted_size(),
+        hits,
+        misses,
+        hit_ratio: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+    })
+}
+
+async fn handle_purge(State(state): State<AppState>, Query(query): Query<KeyQuery>) -> Result<StatusCode, Response> {
+    let config = state.config.load();
+    let (site, path) = query.resolve(&config).map_err(IntoResponse::into_response)?;
+    // an alias shares the entry of what it points at
+    let path = config.aliases.get(&path).unwrap_or(&path);
+    state.cache.invalidate(&format!("{site}{path}")).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct InspectResponse<'a> {
+    /// Cache key and invalidation payload for the path
+    key: String,
+    /// Path actually looked up, when the requested one is an alias
+    alias_of: Option<&'a str>,
+    stored: Stored<'a>,
+    /// Seconds since the entry was cached, or `None` when it isn't
+    cached_age_seconds: Option<f64>,
+}
+
+/// What a lookup finds in redis right now, which may differ from what's cached
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Stored<'a> {
+    Empty,
+    Asset {
+        mime: &'a str,
+        size: usize,
+        last_modified: Option<String>,
+        streamed: bool,
+    },
+    Card(&'a Card),
+    /// A card that's stored but gets served as missing or fails to load
+    Rejected {
+        stored: &'a str,
+        reason: &'a str,
+    },
+}
+
+async fn handle_inspect(State(state): State<AppState>, Query(query): Query<KeyQuery>) -> Result<Response, Response> {
+    let config = state.config.load_full();
+    let (site, requested) = query.resolve(&config).map_err(IntoResponse::into_response)?;
+    let path = config.aliases.get(&requested).map(String::as_str);
+    let alias_of = path.map(|_| requested.as_str());
+    let path = path.unwrap_or(&requested);
+    let key = format!("{site}{path}");
+
+    let (entry, rejected) = async {
+        let mut redis = state.store.get().await?;
+        let entry = load_entry(&mut redis, &site, path, &config).await;
+        if !matches!(entry, Ok(CacheEntry::Empty) | Err(_)) {
+            return Ok((entry?, None));
+        }
+        // a card that can't be served looks the same as a missing or broken
+        // entry, so what's actually stored gets looked at
+        let stored = telemetry::redis_call("get_card", redis.get::<_, Option<String>>(config.card_key(&site, path))).await?;
+        match stored.and_then(|v| Some((decode_card(path, &v, &config).err()?.to_string(), v))) {
+            Some(rejected) => eyre::Ok((CacheEntry::Empty, Some(rejected))),
+            None => Ok((entry?, None)),
+        }
+    }
+    .await
+    .map_err(store_error)?;
+    let stored = match &entry {
+        CacheEntry::Empty => match &rejected {
+            Some((reason, stored)) => Stored::Rejected { stored, reason },
+            None => Stored::Empty,
+        },
+        CacheEntry::Asset(asset) => Stored::Asset {
+            mime: &asset.mime,
+            size: asset.body.len(),
+            last_modified: asset.last_modified.map(httpdate::fmt_http_date),
+            streamed: false,
+        },
+        CacheEntry::Streamed(asset) => Stored::Asset {
+            mime: &asset.mime,
+            size: asset.len,
+            last_modified: asset.last_modified.map(httpdate::fmt_http_date),
+            streamed: true,
+        },
+        CacheEntry::Card(card, _) => Stored::Card(card),
+    };
+    let cached_age_seconds = state.cache.get(&key).map(|v| v.inserted_at.elapsed().as_secs_f64());
+
+    Ok(Json(InspectResponse {
+        key,
+        alias_of,
+        stored,
+        cached_age_seconds,
+    })
+    .into_response())
+}
+
+#[derive(Serialize)]
+struct AnalyticsResponse {
+    key: String,
+    /// Browser redirects counted so far, which stays at zero unless
+    /// `track_clicks` is on
+    clicks: u64,
+    track_clicks: bool,
+    /// Cache hits and misses for the path on this instance since it started,
+    /// or `None` unless `track_path_stats` is on. `/_/stats` has the totals
+    cache: Option<PathStats>,
+}
+
+async fn handle_analytics(State(state): State<AppState>, Query(query): Query<KeyQuery>) -> Result<Response, Response> {
+    let config = state.config.load_full();
+    let (site, path) = query.resolve(&config).map_err(IntoResponse::into_response)?;
+    // clicks are counted against what an alias points at
+    let path = config.aliases.get(&path).unwrap_or(&path);
+
+    let clicks = async {
+        let mut redis = state.store.get().await?;
+        let clicks = telemetry::redis_call("get_clicks", redis.get::<_, Option<u64>>(config.clicks_key(&site, path))).await?;
+        eyre::Ok(clicks.unwrap_or(0))
+    }
+    .await
+    .map_err(store_error)?;
+
+    let key = format!("{site}{path}");
+    let cache = config
+        .track_path_stats
+        .then(|| state.stats.paths.lock().unwrap().get(&key).copied().unwrap_or_default());
+
+    Ok(Json(AnalyticsResponse {
+        key,
+        clicks,
+        track_clicks: config.track_clicks,
+        cache,
+    })
+    .into_response())
+}
+
+/// Path to look at, naming the site too when `host_keys` is on
+#[derive(Deserialize)]
+struct KeyQuery {
+    path: String,
+    #[serde(default)]
+    site: Option<String>,
+}
+
+impl KeyQuery {
+    /// Splits into the site part of keys and the path, in the same shape
+    /// lookups use, so what gets written is what gets found
+    fn resolve(&self, config: &Config) -> Result<(String, String), (StatusCode, &'static str)> {
+        let site = match (&self.site, config.host_keys) {
+            (Some(site), true) => format!("{}:", site.to_lowercase()),
+            (None, true) => return Err((StatusCode::BAD_REQUEST, "missing site")),
+            (Some(_), false) => return Err((StatusCode::BAD_REQUEST, "site is only used with host_keys")),
+            (None, false) => String::new(),
+        };
+        Ok((site, config.normalize_path(&self.path)))
+    }
+}
+
+async fn handle_put_card(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+    body: Bytes,
+) -> Result<StatusCode, Response> {
+    let config = state.config.load_full();
+    let (site, path) = query.resolve(&config).map_err(IntoResponse::into_response)?;
+    let card: Card =
+        serde_json::from_slice(&body).map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid card: {err}")).into_response())?;
+    card.validate(&config)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid card: {err}")).into_response())?;
+
+    let result = async {
+        let mut redis = state.store.get().await?;
+        telemetry::redis_call(
+            "set_card",
+            redis.set::<_, _, ()>(config.card_key(&site, &path), serde_json::to_string(&card)?),
+        )
+        .await?;
+        publish_invalidation(&state, &config, &format!("{site}{path}")).await;
+        eyre::Ok(())
+    }
+    .await;
+    result.map_err(store_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_put_asset(
+    State(state): State<AppState>,
+    Query(query): Query<KeyQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, Response> {
+    let config = state.config.load_full();
+    let (site, path) = query.resolve(&config).map_err(IntoResponse::into_response)?;
+    let mime = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_mime)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "missing or invalid content type").into_response())?;
+    if body.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "empty asset").into_response());
+    }
+    let mut value = Vec::with_capacity(mime.len() + 1 + body.len());
+    value.extend_from_slice(mime.as_bytes());
+    value.push(b';');
+    value.extend_from_slice(&body);
+    let modified = SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0);
+
+    let result = async {
+        let mut redis = state.store.get().await?;
+        telemetry::redis_call("set_asset", redis.set::<_, _, ()>(config.asset_key(&site, &path), value)).await?;
+        telemetry::redis_call(
+            "set_modified",
+            redis.set::<_, _, ()>(config.modified_key(&site, &path), modified),
+        )
+        .await?;
+        publish_invalidation(&state, &config, &format!("{site}{path}")).await;
+        eyre::Ok(())
+    }
+    .await;
+    result.map_err(store_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Takes the type and subtype of a content type, dropping parameters like
+/// charset since a `;` ends the mime type in a stored asset
+fn parse_mime(content_type: &str) -> Option<String> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let (kind, subtype) = mime.split_once('/')?;
+    let is_token = |v: &str| !v.is_empty() && v.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b));
+    (is_token(kind) && is_token(subtype)).then_some(mime)
+}
+
+/// Drops a changed entry here and tells every other instance to as well. A
+/// failed publish only leaves the others stale until their entries expire
+async fn publish_invalidation(state: &AppState, config: &Config, key: &str) {
+    state.cache.invalidate(key).await;
+    let result = async {
+        let mut redis = state.store.get().await?;
+        telemetry::redis_call(
+            "publish_invalidation",
+            redis.publish::<_, _, ()>(&config.invalidations_channel, key),
+        )
+        .await?;
+        eyre::Ok(())
+    }
+    .await;
+    if let Err(err) = result {
+        warn!("failed to publish invalidation for {key:?}: {err:?}");
+    }
+}
+
+fn store_error(err: eyre::Report) -> Response {
+    if store::is_unavailable(&err) {
+        warn!("redis unavailable: {err:?}");
+        StatusCode::SERVICE_UNAVAILABLE.into_response()
+    } else {
+        error!("admin error: {err:?}");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_credentials() {
+        let encode = |v: &str| STANDARD.encode(v);
+        assert_eq!(
+            decode_basic(&encode("user:pass")),
+            Some(("user".to_string(), "pass".to_string()))
+        );
+        // only the first colon splits, since passwords can have them
+        assert_eq!(
+            decode_basic(&format!(" {} ", encode("user:pa:ss"))),
+            Some(("user".to_string(), "pa:ss".to_string()))
+        );
+        assert_eq!(decode_basic(&encode(":")), Some((String::new(), String::new())));
+        assert_eq!(decode_basic(&encode("userpass")), None);
+        assert_eq!(decode_basic("not base64!"), None);
+        assert_eq!(decode_basic(&STANDARD.encode([0xff, b':', 0xfe])), None);
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,341 @@
+// See license info in LICENSE file
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use hyper::server::conn::AddrStream;
+use ppp::{v1, v2, HeaderResult, PartialResult};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tower_http::add_extension::AddExtension;
+use tracing::warn;
+
+use crate::AppState;
+
+/// Longest a proxy may take to send its header after connecting
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound on a header, leaving room for v2 extensions
+const PROXY_HEADER_MAX_BYTES: usize = 4096;
+
+/// Address of the client behind a connection, stored in request extensions
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Client ip after following any trusted proxies, stored in request extensions
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// Marks requests that came straight from a trusted proxy, whose forwarded
+/// headers can be believed
+#[derive(Clone, Copy, Debug)]
+pub struct FromTrustedProxy;
+
+/// Ip or cidr range of proxies allowed to report the client address
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct ProxyRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl ProxyRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        (net ^ ip).checked_shr(bits - self.prefix).unwrap_or(0) == 0
+    }
+}
+
+impl TryFrom<String> for ProxyRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((&value, ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("trusted proxy {value:?} must be an ip or cidr range"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("trusted proxy {value:?} has an invalid prefix length"))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Works out the [`ClientIp`] of a request, walking `X-Forwarded-For` back
+/// past any trusted proxies
+pub async fn resolve_client(State(state): State<AppState>, mut request: Request<Body>, next: Next<Body>) -> Response {
+    let config = state.config.load();
+    let trusted = &config.trusted_proxies;
+    let peer = request.extensions().get::<ClientAddr>().map(|v| v.0.ip());
+    // no peer means a unix socket, which only a local proxy can reach
+    if peer.is_none_or(|ip| is_trusted(trusted, ip)) {
+        request.extensions_mut().insert(FromTrustedProxy);
+    }
+    if let Some(ip) = client_ip(peer, request.headers(), trusted) {
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+fn is_trusted(trusted: &[ProxyRange], ip: IpAddr) -> bool {
+    trusted.iter().any(|range| range.contains(ip))
+}
+
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[ProxyRange]) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| is_trusted(trusted, ip);
+    if peer.is_some_and(|ip| !is_trusted(ip)) {
+        return peer;
+    }
+    // each proxy appends the address it saw, so the first untrusted entry from
+    // the right is the client. anything before it could be made up
+    let mut client = peer;
+    let entries = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .rev()
+        .flat_map(|v| v.to_str().unwrap_or_default().rsplit(','));
+    for entry in entries {
+        let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = Some(ip);
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Binds `addr`, setting whether an ipv6 socket takes ipv4 too rather than
+/// leaving it to the os default
+pub fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        match socket.set_only_v6(ipv6_only) {
+            // some systems can't do dual-stack, which still leaves ipv6 usable
+            Err(err) if !ipv6_only => warn!("failed to take ipv4 connections on {addr}: {err}"),
+            // whereas quietly taking ipv4 would go against what was asked for
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+    }
+    // same as what a plain bind does, so a restart can reuse the port
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Tags every request with its [`ClientAddr`], taking it from a PROXY protocol
+/// header instead of the peer when `proxy_protocol` is set
+#[derive(Clone, Copy)]
+pub struct ClientAddrAcceptor {
+    pub proxy_protocol: bool,
+}
+
+impl<S: Send + 'static> Accept<AddrStream, S> for ClientAddrAcceptor {
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+    type Service = AddExtension<S, ClientAddr>;
+    type Stream = Prefixed<AddrStream>;
+
+    fn accept(&self, mut stream: AddrStream, service: S) -> Self::Future {
+        let proxy_protocol = self.proxy_protocol;
+        Box::pin(async move {
+            // ipv4 clients of a dual-stack socket show up as mapped addresses
+            let peer = stream.remote_addr();
+            let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+            let (addr, prefix) = if proxy_protocol {
+                let (addr, prefix) = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for proxy header")))
+                    .map_err(|err| {
+                        warn!("failed to read proxy header from {peer}: {err}");
+                        err
+                    })?;
+                // health checks from the proxy itself don't carry an address
+                (addr.unwrap_or(peer), prefix)
+            } else {
+                (peer, Vec::new())
+            };
+            Ok((
+                Prefixed {
+                    prefix,
+                    read: 0,
+                    inner: stream,
+                },
+                AddExtension::new(service, ClientAddr(addr)),
+            ))
+        })
+    }
+}
+
+/// Reads a v1 or v2 header off the stream, returning the source address it
+/// carries and whatever was read past its end
+async fn read_proxy_header(stream: &mut AddrStream) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before proxy header",
+            ));
+        }
+        let header = HeaderResult::parse(&buf);
+        if header.is_incomplete() {
+            if buf.len() >= PROXY_HEADER_MAX_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "proxy header too long"));
+            }
+            continue;
+        }
+        let (len, addr) = match header {
+            HeaderResult::V1(Ok(header)) => (header.header.len(), match header.addresses {
+                v1::Addresses::Tcp4(v) => Some(SocketAddr::from((v.source_address, v.source_port))),
+                v1::Addresses::Tcp6(v) => Some(SocketAddr::from((v.source_address, v.source_port))),
+                v1::Addresses::Unknown => None,
+            }),
+            HeaderResult::V2(Ok(header)) => (header.len(), match header.addresses {
+                v2::Addresses::IPv4(v) => Some(SocketAddr::from((v.source_address, v.source_port))),
+                v2::Addresses::IPv6(v) => Some(SocketAddr::from((v.source_address, v.source_port))),
+                _ => None,
+            }),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid proxy header")),
+        };
+        return Ok((addr, buf.split_off(len)));
+    }
+}
+
+/// Stream that replays bytes read ahead of the inner stream before reading
+/// from it again
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    read: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.read);
+            buf.put_slice(&this.prefix[this.read..this.read + len]);
+            this.read += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str) -> ProxyRange {
+        ProxyRange::try_from(value.to_string()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let exact = range("10.0.0.1/32");
+        assert!(exact.contains(ip("10.0.0.1")));
+        assert!(!exact.contains(ip("10.0.0.2")));
+        assert!(range("10.0.0.1").contains(ip("10.0.0.1")));
+
+        let subnet = range("10.0.0.0/8");
+        assert!(subnet.contains(ip("10.255.0.1")));
+        assert!(!subnet.contains(ip("11.0.0.1")));
+        // ipv4 clients of a dual-stack socket show up mapped
+        assert!(subnet.contains(ip("::ffff:10.1.2.3")));
+        assert!(!subnet.contains(ip("fd00::1")));
+
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!range("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!range("2001:db8::/32").contains(ip("2001:db9::1")));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "proxy", ""] {
+            assert!(ProxyRange::try_from(invalid.to_string()).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn client_ip_follows_trusted_proxies() {
+        let trusted = [range("10.0.0.0/8")];
+        let client = |peer: Option<&str>, header: &str| client_ip(peer.map(ip), &forwarded(header), &trusted);
+
+        // an untrusted peer's header is ignored
+        assert_eq!(client(Some("203.0.113.9"), "1.2.3.4"), Some(ip("203.0.113.9")));
+        // the first untrusted entry from the right is the client, and whatever
+        // comes before it could be made up
+        assert_eq!(client(Some("10.0.0.1"), "6.6.6.6, 1.2.3.4, 10.0.0.2"), Some(ip("1.2.3.4")));
+        assert_eq!(client(Some("::ffff:10.0.0.1"), "1.2.3.4"), Some(ip("1.2.3.4")));
+        // no peer means a unix socket, which is trusted
+        assert_eq!(client(None, "1.2.3.4"), Some(ip("1.2.3.4")));
+        // garbage stops the walk at the last address that could be believed
+        assert_eq!(client(Some("10.0.0.1"), "1.2.3.4, garbage"), Some(ip("10.0.0.1")));
+        assert_eq!(client(Some("10.0.0.1"), "garbage, 1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(client(Some("10.0.0.1"), "garbage, 10.0.0.2"), Some(ip("10.0.0.2")));
+        // a chain of only trusted proxies leaves the furthest one
+        assert_eq!(client(Some("10.0.0.1"), "10.0.0.3, 10.0.0.2"), Some(ip("10.0.0.3")));
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.1")), &HeaderMap::new(), &trusted),
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
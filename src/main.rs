@@ -1,252 +1,2711 @@
 // See license info in LICENSE file
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter, Write},
+    future::Future,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroU64},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use arc_swap::ArcSwap;
 use axum::{
-    body::Body,
+    body::{Body, Bytes, HttpBody},
     debug_handler,
-    extract::Query,
-    http::{Request, StatusCode},
-    response::{IntoResponse, Response},
+    extract::{Query, State},
+    http::{header::CONTENT_LENGTH, uri::Authority, Extensions, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
     routing::get,
     Router,
 };
-use bb8::Pool;
-use bb8_redis::{redis::AsyncCommands, RedisConnectionManager};
-use eyre::ContextCompat;
+use axum_server::{
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle, HttpConfig,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use eyre::{ContextCompat, WrapErr};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
 };
-use futures::StreamExt;
-use moka::future::Cache;
+use futures::{FutureExt, StreamExt, TryFutureExt, TryStreamExt};
+use http_body::{LengthLimitError, Limited};
+use hyper::server::accept;
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::PrometheusHandle;
+use moka::{future::Cache, Expiry};
+use redis::{aio::PubSub, AsyncCommands};
+use ring::{digest, hmac};
 use serde::{Deserialize, Serialize};
-use tokio::{select, sync::oneshot};
+use tokio::{
+    net::UnixListener,
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
+};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::{
+        predicate::{NotForContentType, Predicate},
+        CompressionLayer, DefaultPredicate,
+    },
+    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    admin::Stats,
+    listen::{ClientAddrAcceptor, FromTrustedProxy, ProxyRange},
+    ratelimit::RateLimiter,
+    store::{Store, StoreConnection},
+    telemetry::LogFormat,
+};
+
+mod admin;
+mod listen;
+mod ratelimit;
+mod store;
+mod telemetry;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
 
-    let config: Config = Figment::new()
-        .merge(Toml::file("shim.toml"))
-        .merge(Env::prefixed("SHIM_"))
-        .extract()?;
+    let config = load_config()?;
+    config.validate()?;
+    let config = Arc::new(config);
+    // what requests see, which a reload swaps out
+    let live_config = Arc::new(ArcSwap::from(config.clone()));
 
-    let manager = bb8_redis::RedisConnectionManager::new(config.database_url)?;
-    let pool = bb8::Pool::builder().build(manager).await?;
+    let _tracing = telemetry::init_tracing(&config)?;
 
-    let cache = Cache::<String, CacheEntry>::builder()
-        .time_to_idle(Duration::from_secs(60 * 60))
-        .weigher(|_, v| match v {
+    let store = retry_startup(&config, "connect to redis", || Store::connect(&config)).await?;
+    // subscribed before warming so nothing published meanwhile is missed
+    let mut invalidations = retry_startup(&config, "subscribe to invalidations", || subscribe(&store, &config)).await?;
+
+    let weigher_config = live_config.clone();
+    let mut cache_builder = Cache::<String, Cached>::builder()
+        .time_to_idle(Duration::from_secs(config.cache_idle_seconds.get()))
+        .expire_after(CacheExpiry(live_config.clone()))
+        .weigher(move |_, v| match &v.entry {
             CacheEntry::Empty => 0,
-            CacheEntry::Asset(v) => (v.0.len() + v.1.len()) as u32,
-            CacheEntry::Card(v) => std::mem::size_of_val(v) as u32,
-        })
-        .build();
+            CacheEntry::Asset(v) => (v.mime.len() + v.body.len() + v.etag.len()) as u32,
+            // embeds get rendered after insertion, so room is set aside for
+            // as many as the card could end up holding
+            CacheEntry::Card(v, _) => {
+                let renders = if weigher_config.load().public_base_from_host {
+                    MAX_RENDERED_EMBEDS
+                } else {
+                    1
+                };
+                let fields = v.weight();
+                (fields + renders * (EMBED_OVERHEAD_BYTES + EMBED_FIELD_REPEATS * fields)) as u32
+            }
+            CacheEntry::Streamed(v) => (v.key.len() + v.mime.len()) as u32,
+        });
+    if let Some(ttl) = config.cache_ttl_seconds {
+        // hard bound on staleness in case an invalidation gets missed
+        cache_builder = cache_builder.time_to_live(Duration::from_secs(ttl.get()));
+    }
+    if let Some(max_bytes) = config.cache_max_bytes {
+        cache_builder = cache_builder.max_capacity(max_bytes);
+    }
+    let cache = cache_builder.support_invalidation_closures().build();
+
+    if config.warm_cache {
+        if let Err(err) = warm_cache(&store, &cache, &config).await {
+            warn!("failed to warm cache: {err:?}");
+        }
+    }
 
-    let mut invalidations = pool.dedicated_connection().await?.into_pubsub();
-    invalidations.subscribe("invalidations").await?;
     let (invalidations_kill_tx, mut invalidations_kill_rx) = oneshot::channel();
-    let invalidations_task = tokio::spawn((|| {
+    let invalidations_task = tokio::spawn({
         let cache = cache.clone();
+        let store = store.clone();
+        let config = config.clone();
+        async move {
+            loop {
+                let mut stream = invalidations.into_on_message();
+                while let Some(item) = select! {
+                    v = stream.next() => v,
+                    _ = &mut invalidations_kill_rx => return,
+                } {
+                    invalidate(&cache, &String::from_utf8_lossy(item.get_payload_bytes())).await;
+                }
+
+                // the connection dropped, which is also how a failover shows up
+                warn!("lost invalidations subscription, reconnecting");
+                let resubscribe = async {
+                    loop {
+                        match subscribe(&store, &config).await {
+                            Ok(v) => break v,
+                            Err(err) => {
+                                warn!("failed to resubscribe to invalidations: {err:?}");
+                                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                            }
+                        }
+                    }
+                };
+                invalidations = select! {
+                    v = resubscribe => v,
+                    _ = &mut invalidations_kill_rx => return,
+                };
+                // anything published while disconnected was missed
+                cache.invalidate_all();
+            }
+        }
+    });
+
+    let state = AppState {
+        store,
+        cache,
+        config: live_config,
+        stats: Default::default(),
+        metrics: if config.enable_metrics {
+            Some(telemetry::install_recorder()?)
+        } else {
+            None
+        },
+        limiter: config
+            .rate_limit
+            .map(|limit| RateLimiter::new(limit, config.rate_limit_window_seconds)),
+        not_found_page: match &config.not_found_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .wrap_err_with(|| format!("failed to read not_found_file {}", path.display()))?
+                    .into(),
+            ),
+            None => config.not_found_template.as_deref().map(Into::into),
+        },
+        favicon: match &config.favicon {
+            Some(path) => Some(StaticFile {
+                content_type: match path.extension().and_then(|v| v.to_str()) {
+                    Some("png") => "image/png",
+                    Some("svg") => "image/svg+xml",
+                    _ => "image/vnd.microsoft.icon",
+                },
+                body: std::fs::read(path)
+                    .wrap_err_with(|| format!("failed to read favicon {}", path.display()))?
+                    .into(),
+            }),
+            None => None,
+        },
+        robots_txt: match &config.robots_txt_file {
+            Some(path) => std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("failed to read robots_txt_file {}", path.display()))?
+                .into(),
+            None => config.robots_txt.as_str().into(),
+        },
+    };
+    // registered before serving, since a hangup would otherwise end the process
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn({
+        let state = state.clone();
         async move {
-            let mut stream = invalidations.into_on_message();
-            while let Some(item) = select! {
-                v = stream.next() => v,
-                _ = &mut invalidations_kill_rx => None,
-            } {
-                cache
-                    .invalidate(&String::from_utf8_lossy(item.get_payload_bytes()).to_string())
-                    .await;
+            while hangups.recv().await.is_some() {
+                reload_config(&state);
             }
         }
-    })());
+    });
 
-    let public_base: &'static str = Box::leak(config.public_base.clone().into_boxed_str());
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/_/oembed.json", get(handle_oembed))
-        .fallback(move |r| handle(r, pool.clone(), cache.clone(), public_base));
+        .route("/_/health", get(handle_health))
+        .route("/favicon.ico", get(handle_favicon))
+        .route("/robots.txt", get(handle_robots_txt))
+        .route("/_/ready", get(handle_ready))
+        // mounted even when disabled, so a reload can turn them on
+        .merge(admin::router(state.clone()));
+    if config.enable_metrics {
+        app = app.route("/_/metrics", get(telemetry::handle_metrics));
+    }
+    let mut app = app.fallback(handle);
+    if let Some(cors) = cors_layer(&config.cors_allow_origins)? {
+        app = app.layer(cors);
+    }
+    let app = app
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(CompressionLayer::new().compress_when(compression_predicate()))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_time))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_body))
+        .layer(middleware::from_fn_with_state(state.clone(), ratelimit::limit_requests))
+        .layer(middleware::from_fn_with_state(state.clone(), telemetry::track_requests))
+        .layer(middleware::from_fn_with_state(state.clone(), listen::resolve_client))
+        .layer(middleware::from_fn_with_state(state.clone(), security_headers))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state);
 
     let (server_kill_tx, server_kill_rx) = oneshot::channel();
-    let server = axum::Server::bind(&config.listen_on)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(async move {
-            let _ = server_kill_rx.await;
-        });
+    let shutdown = async move {
+        let _ = server_kill_rx.await;
+    };
+    // pings let dead http/2 connections get dropped rather than holding up a
+    // graceful shutdown
+    let http2_keep_alive = Some(Duration::from_secs(config.http2_keep_alive_seconds.get()));
+    let server = match (config.listen_on, &config.listen_unix) {
+        (Some(addr), None) => {
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            let server = axum_server::from_tcp(listen::bind_tcp(addr, config.ipv6_only)?)
+                .http_config(HttpConfig::new().http2_keep_alive_interval(http2_keep_alive).build())
+                .handle(handle);
+            let acceptor = ClientAddrAcceptor {
+                proxy_protocol: config.proxy_protocol,
+            };
+            match (&config.tls_cert, &config.tls_key) {
+                (Some(cert), Some(key)) => {
+                    let tls = RustlsConfig::from_pem_file(cert, key).await?;
+                    info!("listening on {addr} with tls");
+                    server
+                        .acceptor(RustlsAcceptor::new(tls).acceptor(acceptor))
+                        .serve(app.into_make_service())
+                        .err_into::<eyre::Report>()
+                        .boxed()
+                }
+                _ => {
+                    info!("listening on {addr}");
+                    server
+                        .acceptor(acceptor)
+                        .serve(app.into_make_service())
+                        .err_into::<eyre::Report>()
+                        .boxed()
+                }
+            }
+        }
+        (None, Some(path)) => {
+            // a socket left behind by an unclean exit would make the bind fail.
+            // anything else is left alone, in case the path is a typo
+            if std::fs::symlink_metadata(path).is_ok_and(|v| v.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            let accept = accept::poll_fn(move |cx| listener.poll_accept(cx).map(|v| Some(v.map(|(stream, _)| stream))));
+            info!("listening on {}", path.display());
+            axum::Server::builder(accept)
+                .http2_keep_alive_interval(http2_keep_alive)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(shutdown)
+                .err_into()
+                .boxed()
+        }
+        _ => unreachable!("checked on startup"),
+    };
 
     let (server_shutdown_tx, server_shutdown_rx) = oneshot::channel();
     tokio::spawn(async move {
         if let Err(err) = server.await {
-            println!("server error: {err:?}");
+            error!("server error: {err:?}");
         }
         let _ = server_shutdown_tx.send(());
     });
 
+    // orchestrators mostly stop things with SIGTERM rather than SIGINT
+    let mut terminate = signal(SignalKind::terminate())?;
     tokio::spawn(async move {
-        let _ = tokio::signal::ctrl_c().await;
+        select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
         let _ = invalidations_kill_tx.send(());
         let _ = server_kill_tx.send(());
     });
 
     invalidations_task.await?;
-    let _ = server_shutdown_rx.await;
+    // whatever is still open once this runs out gets cut off as the runtime
+    // shuts down
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_seconds.get());
+    if tokio::time::timeout(shutdown_timeout, server_shutdown_rx).await.is_err() {
+        warn!("requests still in flight after {shutdown_timeout:?}, closing their connections");
+    }
 
     Ok(())
 }
 
-async fn handle(
-    request: Request<Body>,
-    pool: Pool<RedisConnectionManager>,
-    cache: Cache<String, CacheEntry>,
-    public_base: &str,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    handle_inner(request, pool, cache, public_base).await.map_err(|err| {
-        println!("handler error: {err:?}");
-        let dbg = format!("{err:?}");
-        let inner = ansi_to_html::convert(&dbg, true, true)
-            .unwrap_or(dbg)
-            .trim()
-            .replace('\n', "<br>");
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("Content-Type", "text/html")
-            .body(format!(
-                "<!doctype html><h1>500 Internal Server Exception</h1><code>{inner}</code>"
-            ))
-            .unwrap()
-    })
+/// Reads `shim.toml` and `SHIM_` variables. Validating is left to the caller,
+/// since a reload first puts back what it can't change
+fn load_config() -> eyre::Result<Config> {
+    let mut config = Figment::new()
+        .merge(Toml::file("shim.toml"))
+        .merge(Env::prefixed("SHIM_"))
+        .extract::<Config>()?;
+    config.normalize_aliases()?;
+    Ok(config)
 }
 
-async fn handle_inner(
-    request: Request<Body>,
-    pool: Pool<RedisConnectionManager>,
-    cache: Cache<String, CacheEntry>,
-    public_base: &str,
-) -> eyre::Result<impl IntoResponse> {
-    let path = request.uri().path().trim_matches('/');
+/// Puts back the running values of settings only read on startup, so nothing
+/// ends up half using a change that needs a restart
+fn keep_restart_only(config: &mut Config, running: &Config) {
+    fn keep<T: PartialEq + Clone>(key: &str, value: &mut T, running: &T) {
+        if value != running {
+            warn!("ignoring change to {key}, which needs a restart");
+            value.clone_from(running);
+        }
+    }
+    keep("database_url", &mut config.database_url, &running.database_url);
+    keep("redis_ca_file", &mut config.redis_ca_file, &running.redis_ca_file);
+    keep(
+        "redis_tls_insecure",
+        &mut config.redis_tls_insecure,
+        &running.redis_tls_insecure,
+    );
+    keep("sentinel_master", &mut config.sentinel_master, &running.sentinel_master);
+    keep(
+        "sentinel_addresses",
+        &mut config.sentinel_addresses,
+        &running.sentinel_addresses,
+    );
+    keep("pool_max_size", &mut config.pool_max_size, &running.pool_max_size);
+    keep("pool_min_idle", &mut config.pool_min_idle, &running.pool_min_idle);
+    keep(
+        "pool_timeout_seconds",
+        &mut config.pool_timeout_seconds,
+        &running.pool_timeout_seconds,
+    );
+    keep("redis_timeout_ms", &mut config.redis_timeout_ms, &running.redis_timeout_ms);
+    keep("listen_on", &mut config.listen_on, &running.listen_on);
+    keep("ipv6_only", &mut config.ipv6_only, &running.ipv6_only);
+    keep("listen_unix", &mut config.listen_unix, &running.listen_unix);
+    keep(
+        "http2_keep_alive_seconds",
+        &mut config.http2_keep_alive_seconds,
+        &running.http2_keep_alive_seconds,
+    );
+    keep(
+        "shutdown_timeout_seconds",
+        &mut config.shutdown_timeout_seconds,
+        &running.shutdown_timeout_seconds,
+    );
+    keep("tls_cert", &mut config.tls_cert, &running.tls_cert);
+    keep("tls_key", &mut config.tls_key, &running.tls_key);
+    keep("proxy_protocol", &mut config.proxy_protocol, &running.proxy_protocol);
+    keep("rate_limit", &mut config.rate_limit, &running.rate_limit);
+    keep(
+        "rate_limit_window_seconds",
+        &mut config.rate_limit_window_seconds,
+        &running.rate_limit_window_seconds,
+    );
+    keep(
+        "cache_idle_seconds",
+        &mut config.cache_idle_seconds,
+        &running.cache_idle_seconds,
+    );
+    keep("cache_ttl_seconds", &mut config.cache_ttl_seconds, &running.cache_ttl_seconds);
+    keep("cache_max_bytes", &mut config.cache_max_bytes, &running.cache_max_bytes);
+    keep(
+        "cors_allow_origins",
+        &mut config.cors_allow_origins,
+        &running.cors_allow_origins,
+    );
+    keep("favicon", &mut config.favicon, &running.favicon);
+    keep("robots_txt", &mut config.robots_txt, &running.robots_txt);
+    keep("robots_txt_file", &mut config.robots_txt_file, &running.robots_txt_file);
+    keep(
+        "not_found_template",
+        &mut config.not_found_template,
+        &running.not_found_template,
+    );
+    keep("not_found_file", &mut config.not_found_file, &running.not_found_file);
+    keep(
+        "invalidations_channel",
+        &mut config.invalidations_channel,
+        &running.invalidations_channel,
+    );
+    keep("log_format", &mut config.log_format, &running.log_format);
+    keep("otlp_endpoint", &mut config.otlp_endpoint, &running.otlp_endpoint);
+    keep("enable_metrics", &mut config.enable_metrics, &running.enable_metrics);
+    keep("startup_retries", &mut config.startup_retries, &running.startup_retries);
+    keep("warm_cache", &mut config.warm_cache, &running.warm_cache);
+    keep("warm_cache_max", &mut config.warm_cache_max, &running.warm_cache_max);
+}
 
-    let (entry, cache_status) = match cache.get(path) {
-        Some(v) => (v, "hit"),
-        None => {
-            let mut redis = pool.get().await?;
+/// Swaps in a freshly read config for requests from now on. The cache is
+/// flushed since what gets loaded for a path depends on the config too
+fn reload_config(state: &AppState) {
+    let config = load_config().and_then(|mut config| {
+        keep_restart_only(&mut config, &state.config.load());
+        config.validate()?;
+        Ok(config)
+    });
+    let config = match config {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("failed to reload config, keeping the current one: {err:?}");
+            return;
+        }
+    };
+    state.config.store(Arc::new(config));
+    state.cache.invalidate_all();
+    info!("reloaded config");
+}
 
-            let asset = redis.get::<_, Option<Vec<u8>>>(format!("asset:{path}")).await?;
-            let entry = match asset {
-                Some(v) => {
-                    let mut iter = v.splitn(2, |x| *x == b';');
-                    let mime = iter.next().wrap_err("asset iterator exhausted before first split")?;
-                    let body = iter.next().wrap_err("asset iterator exhausted before body")?;
-                    CacheEntry::Asset((String::from_utf8_lossy(mime).to_string(), body.into()))
-                }
-                None => {
-                    let card = redis.get::<_, Option<String>>(format!("card:{path}")).await?;
-                    match card {
-                        Some(s) => CacheEntry::Card(Arc::new(serde_json::from_str(&s)?)),
-                        None => CacheEntry::Empty,
-                    }
-                }
+/// Delay before the first startup retry, doubled after each failure
+const STARTUP_RETRY_DELAY: Duration = Duration::from_millis(500);
+const STARTUP_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Runs a startup step until it succeeds or `startup_retries` runs out, since
+/// redis often isn't up yet when the shim is started alongside it
+async fn retry_startup<T, F: Future<Output = eyre::Result<T>>>(
+    config: &Config,
+    step: &str,
+    mut f: impl FnMut() -> F,
+) -> eyre::Result<T> {
+    let mut delay = STARTUP_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt <= config.startup_retries => {
+                warn!(
+                    "failed to {step} (attempt {attempt} of {}), retrying in {delay:?}: {err}",
+                    config.startup_retries + 1
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(STARTUP_RETRY_MAX_DELAY);
+            }
+            Err(err) => return Err(err.wrap_err(format!("failed to {step}"))),
+        }
+    }
+}
+
+/// How long to wait between attempts to get the invalidations subscription back
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+async fn subscribe(store: &Store, config: &Config) -> eyre::Result<PubSub> {
+    let mut pubsub = store.pubsub().await?;
+    pubsub.subscribe(&config.invalidations_channel).await?;
+    Ok(pubsub)
+}
+
+const FLUSH_ALL: &str = "__flush_all__";
+
+/// Handles a message from the invalidations channel, which is either an exact
+/// path, `prefix:` followed by a path prefix, or [`FLUSH_ALL`]. A path that
+/// happens to equal the sentinel is still dropped, just along with everything
+/// else
+async fn invalidate(cache: &Cache<String, Cached>, payload: &str) {
+    if payload == FLUSH_ALL {
+        info!("flushing entire cache");
+        cache.invalidate_all();
+        return;
+    }
+
+    match payload.strip_prefix("prefix:") {
+        Some(prefix) => {
+            let prefix = prefix.to_string();
+            if let Err(err) = cache.invalidate_entries_if(move |k, _| k.starts_with(&prefix)) {
+                error!("failed to invalidate prefix {payload:?}: {err:?}");
+            }
+        }
+        None => cache.invalidate(payload).await,
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    pub store: Store,
+    pub cache: Cache<String, Cached>,
+    pub config: Arc<ArcSwap<Config>>,
+    pub stats: Arc<Stats>,
+    pub metrics: Option<PrometheusHandle>,
+    pub limiter: Option<RateLimiter>,
+    pub not_found_page: Option<Arc<str>>,
+    pub favicon: Option<StaticFile>,
+    pub robots_txt: Arc<str>,
+}
+
+/// File read once on startup and served as is
+#[derive(Clone)]
+struct StaticFile {
+    content_type: &'static str,
+    body: Bytes,
+}
+
+async fn handle(State(state): State<AppState>, request: Request<Body>) -> Result<impl IntoResponse, Response> {
+    let config = state.config.load_full();
+    let wants_json = wants_json(request.headers());
+    handle_inner(request, state).await.map_err(|err| {
+        let unavailable = store::is_unavailable(&err);
+        let (status, title) = if unavailable {
+            warn!("redis unavailable: {err:?}");
+            (StatusCode::SERVICE_UNAVAILABLE, "503 Service Unavailable")
+        } else {
+            error!("handler error: {err:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Exception")
+        };
+        let mut response = if wants_json {
+            let mut body = serde_json::json!({ "error": title });
+            if config.show_error_details {
+                body["details"] = format!("{err:#}").into();
+            }
+            (status, Json(body)).into_response()
+        } else {
+            // the report can name keys and redis hosts, so it stays in the
+            // logs unless asked for
+            let details = if config.show_error_details {
+                let dbg = format!("{err:?}");
+                let inner = ansi_to_html::convert(&dbg, true, true)
+                    .unwrap_or(dbg)
+                    .trim()
+                    .replace('\n', "<br>");
+                format!("<code>{inner}</code>")
+            } else {
+                String::new()
             };
+            (
+                status,
+                [("Content-Type", "text/html")],
+                format!("<!doctype html><h1>{title}</h1>{details}"),
+            )
+                .into_response()
+        };
+        if unavailable {
+            response
+                .headers_mut()
+                .insert("Retry-After", HeaderValue::from_static(UNAVAILABLE_RETRY_AFTER));
+        }
+        response
+    })
+}
+
+/// Whether the client would rather get errors as json than as a page
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("Accept")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.split(';').next().unwrap_or_default().trim())
+        .find(|v| *v == "text/html" || *v == "application/json" || v.ends_with("+json"))
+        .is_some_and(|v| v != "text/html")
+}
+
+/// Headers added to every response when `security_headers` is enabled,
+/// unless the handler already set them
+const SECURITY_HEADERS: [(&str, &str); 3] = [
+    // a wrongly stored asset mime mustn't get sniffed into something runnable
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+];
+
+async fn security_headers(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    let config = state.config.load();
+    if config.security_headers {
+        for (name, value) in SECURITY_HEADERS {
+            headers.entry(name).or_insert(HeaderValue::from_static(value));
+        }
+    }
+    // only ever set with tls, checked when the config is loaded
+    if let Some(max_age) = config.hsts_max_age_seconds {
+        let subdomains = if config.hsts_include_subdomains {
+            "; includeSubDomains"
+        } else {
+            ""
+        };
+        headers.insert(
+            "Strict-Transport-Security",
+            HeaderValue::try_from(format!("max-age={max_age}{subdomains}")).unwrap(),
+        );
+    }
+    response
+}
+
+/// Answers with a 500 when a handler panics, instead of dropping the connection
+fn handle_panic(panic: Box<dyn Any + Send>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    error!("handler panicked: {message}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        [("Content-Type", "text/html")],
+        "<!doctype html><h1>500 Internal Server Exception</h1>",
+    )
+        .into_response()
+}
+
+/// Seconds clients are asked to wait when redis is unavailable
+const UNAVAILABLE_RETRY_AFTER: &str = "5";
 
-            cache.insert(path.to_string(), entry.clone()).await;
-            (entry, "miss")
+#[instrument(skip_all)]
+async fn handle_inner(request: Request<Body>, state: AppState) -> eyre::Result<impl IntoResponse> {
+    let AppState {
+        store,
+        cache,
+        config,
+        stats,
+        not_found_page,
+        ..
+    } = state;
+    // held for the whole request so a reload can't change things partway
+    let config = config.load_full();
+    let raw_path = request.uri().path();
+    let path = match config.trailing_slash {
+        TrailingSlash::Trim => raw_path.trim_matches('/'),
+        TrailingSlash::Strict => raw_path.trim_start_matches('/'),
+        TrailingSlash::Redirect => {
+            let trimmed = raw_path.trim_matches('/');
+            // leading slashes get collapsed too, or `//host/` would send the
+            // browser to another host. `/\host/` would as well, so it's served
+            // without a redirect instead
+            let mut location = format!("/{trimmed}");
+            if !trimmed.is_empty() && raw_path.ends_with('/') && is_local_path(&location) {
+                if let Some(query) = request.uri().query() {
+                    write!(location, "?{query}")?;
+                }
+                return Ok(Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header("Location", location)
+                    .body(Body::empty())?);
+            }
+            trimmed
+        }
+    };
+    // checked before anything is looked up, so unsigned paths can't be probed
+    if let Some(key) = &config.url_signing_key {
+        if !is_signed(key, path, request.uri().query(), SystemTime::now()) {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("invalid signature"))?);
+        }
+    }
+    let lowercased;
+    let path = if config.case_insensitive_paths {
+        lowercased = path.to_lowercase();
+        &lowercased
+    } else {
+        path
+    };
+    // aliases share the entry of what they point at, invalidations included
+    let path = config.aliases.get(path).map_or(path, String::as_str);
+    let site = if config.host_keys {
+        match request_host(&request) {
+            Some(host) => format!("{host}:"),
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("missing host"))?)
+            }
         }
+    } else {
+        String::new()
+    };
+
+    // concurrent misses for the same path share a single load, and a failed
+    // load isn't cached so the next request tries again
+    let cached = cache
+        .entry(format!("{site}{path}"))
+        .or_try_insert_with(async {
+            let start = Instant::now();
+            let mut redis = store
+                .get()
+                .await
+                .inspect_err(|_| counter!("shim_redis_errors_total", "command" => "pool").increment(1))?;
+            let entry = load_entry(&mut redis, &site, path, &config).await?;
+            histogram!("shim_redis_lookup_duration_seconds").record(start.elapsed().as_secs_f64());
+            eyre::Ok(Cached::new(entry))
+        })
+        .await
+        .map_err(SharedError)?;
+    let (cache_status, age) = if cached.is_fresh() {
+        stats.misses.fetch_add(1, Ordering::Relaxed);
+        ("miss", None)
+    } else {
+        stats.hits.fetch_add(1, Ordering::Relaxed);
+        ("hit", Some(cached.value().inserted_at.elapsed()))
     };
+    let entry = cached.into_value().entry;
+    // missing paths are left out, since there's no end to them
+    if config.track_path_stats && !matches!(entry, CacheEntry::Empty) {
+        let mut paths = stats.paths.lock().unwrap();
+        let counts = paths.entry(format!("{site}{path}")).or_default();
+        if age.is_some() {
+            counts.hits += 1;
+        } else {
+            counts.misses += 1;
+        }
+    }
 
-    let response = Response::builder().header("X-Cache-Status", cache_status);
+    counter!("shim_cache_lookups_total", "status" => cache_status).increment(1);
 
-    Ok(match entry {
-        CacheEntry::Empty => response.status(StatusCode::NOT_FOUND).body(Body::from("not found"))?,
-        CacheEntry::Asset((mime, body)) => response
-            .status(StatusCode::OK)
-            .header("Content-Type", mime)
-            .body(Body::from(body))?,
-        CacheEntry::Card(card) => {
+    let mut response = Response::builder().header("X-Cache-Status", cache_status);
+    if let Some(age) = age {
+        response = response.header("Age", age.as_secs());
+    }
+
+    let response = match entry {
+        CacheEntry::Empty => match not_found_page {
+            Some(page) => response
+                .status(StatusCode::NOT_FOUND)
+                .header("Content-Type", "text/html")
+                .body(Body::from(page.to_string()))?,
+            None => response.status(StatusCode::NOT_FOUND).body(Body::from("not found"))?,
+        },
+        CacheEntry::Asset(asset) => {
+            let mut response = response.header("ETag", &asset.etag);
+            if let Some(last_modified) = asset.last_modified {
+                response = response.header("Last-Modified", httpdate::fmt_http_date(last_modified));
+            }
+            let response = response
+                .header("Vary", "Accept-Encoding")
+                .header("Accept-Ranges", "bytes")
+                .header("Cache-Control", &config.asset_cache_control);
+            if asset.is_not_modified(request.headers()) {
+                response.status(StatusCode::NOT_MODIFIED).body(Body::empty())?
+            } else {
+                let len = asset.body.len();
+                match asset.requested_range(request.headers()) {
+                    Some(ByteRange::Satisfiable(range)) => response
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header("Content-Type", asset.mime)
+                        .header("Content-Range", format!("bytes {}-{}/{len}", range.start, range.end - 1))
+                        .body(Body::from(asset.body.slice(range)))?,
+                    Some(ByteRange::Unsatisfiable) => response
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header("Content-Range", format!("bytes */{len}"))
+                        .body(Body::empty())?,
+                    None => response
+                        .status(StatusCode::OK)
+                        .header("Content-Type", asset.mime)
+                        .body(Body::from(asset.body))?,
+                }
+            }
+        }
+        CacheEntry::Streamed(asset) => {
+            let mut response = response;
+            if let Some(last_modified) = asset.last_modified {
+                response = response.header("Last-Modified", httpdate::fmt_http_date(last_modified));
+            }
+            let response = response
+                .header("Vary", "Accept-Encoding")
+                .header("Accept-Ranges", "bytes")
+                .header("Cache-Control", &config.asset_cache_control);
+            if asset.is_not_modified(request.headers()) {
+                response.status(StatusCode::NOT_MODIFIED).body(Body::empty())?
+            } else {
+                let len = asset.len;
+                match asset.requested_range(request.headers()) {
+                    Some(ByteRange::Satisfiable(range)) => response
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header("Content-Type", &asset.mime)
+                        .header("Content-Range", format!("bytes {}-{}/{len}", range.start, range.end - 1))
+                        .header(CONTENT_LENGTH, range.len())
+                        .body(asset.body(store, range))?,
+                    Some(ByteRange::Unsatisfiable) => response
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header("Content-Range", format!("bytes */{len}"))
+                        .body(Body::empty())?,
+                    None => response
+                        .status(StatusCode::OK)
+                        .header("Content-Type", &asset.mime)
+                        .header(CONTENT_LENGTH, len)
+                        .body(asset.body(store, 0..len))?,
+                }
+            }
+        }
+        CacheEntry::Card(card, embeds) => {
+            // the response depends on whether the client is a crawler, and the
+            // embed html may also get compressed
+            let mut response = response.header("Vary", "User-Agent, Accept-Encoding");
+            if let Some(cache_control) = &config.card_cache_control {
+                response = response.header("Cache-Control", cache_control);
+            }
             if request
                 .headers()
                 .get("User-Agent")
                 .and_then(|ua| ua.to_str().ok())
-                .map(|ua| ua.contains("Discordbot"))
+                .map(|ua| config.is_crawler(ua))
                 .unwrap_or(false)
             {
-                // request is from discord, render embed
+                // request is from a link preview crawler, render embed
+                if !config.embed_csp.is_empty() {
+                    let script_hash = format!(
+                        "'sha256-{}'",
+                        STANDARD.encode(digest::digest(&digest::SHA256, card.redirect_script().as_bytes()))
+                    );
+                    response = response.header(
+                        "Content-Security-Policy",
+                        config.embed_csp.replace("{script_hash}", &script_hash),
+                    );
+                }
                 response
                     .status(StatusCode::OK)
                     .header("Content-Type", "text/html")
-                    .body(Body::from(card.build_embed_html(public_base)))?
+                    .body(Body::from(
+                        embeds.get_or_render(&public_base(&request, &config), |public_base| {
+                            card.build_embed_html(&config, public_base)
+                        }),
+                    ))?
             } else {
-                // request is not from discord, redirect
+                // request is from a browser, redirect
+                if !config.redirect_referrer_policy.is_empty() {
+                    response = response.header("Referrer-Policy", &config.redirect_referrer_policy);
+                }
+                let mut location = match request.uri().query() {
+                    // the signature is only for this side of the redirect
+                    Some(query) if config.redirect_preserve_query && config.url_signing_key.is_some() => {
+                        append_query(&card.url, &without_signature(query))
+                    }
+                    Some(query) if config.redirect_preserve_query => append_query(&card.url, query),
+                    _ => card.url.clone(),
+                };
+                if !config.redirect_append_query.is_empty() {
+                    location = merge_query(&location, &config.redirect_append_query);
+                }
+                if config.track_clicks && request.method() != Method::HEAD {
+                    count_click(store.clone(), config.clicks_key(&site, path));
+                }
                 response
-                    .status(StatusCode::PERMANENT_REDIRECT)
-                    .header("Location", card.url.clone())
+                    .status(config.redirect_status.0)
+                    .header("Location", location)
                     .body(Body::empty())?
             }
         }
+    };
+
+    // hyper drops the body of head responses anyway, but do it here so the
+    // length still gets reported
+    Ok(if request.method() == Method::HEAD {
+        let (mut parts, body) = response.into_parts();
+        if let Some(len) = body.size_hint().exact() {
+            parts.headers.entry(CONTENT_LENGTH).or_insert(len.into());
+        }
+        Response::from_parts(parts, Body::empty())
+    } else {
+        response
     })
 }
 
-#[derive(Deserialize)]
-struct Config {
-    pub database_url: String,
-    pub listen_on: SocketAddr,
-    pub public_base: String,
+/// Checks an `If-None-Match` header against an etag, using the weak comparison
+/// the spec asks for
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.strip_prefix("W/").unwrap_or(v) == etag)
 }
 
-#[derive(Clone)]
-enum CacheEntry {
-    Empty,
-    Asset((String, Vec<u8>)),
-    Card(Arc<Card>),
+/// Answers requests still running after `request_timeout_ms` with a 504,
+/// except for asset uploads which get `upload_timeout_ms`
+async fn limit_time(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let timeout = {
+        let config = state.config.load();
+        if request.uri().path() == admin::ASSET_PATH {
+            config.upload_timeout_ms
+        } else {
+            config.request_timeout_ms
+        }
+    };
+    match tokio::time::timeout(Duration::from_millis(timeout.get()), next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => StatusCode::GATEWAY_TIMEOUT.into_response(),
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Card {
-    pub title: String,
-    pub cta: String,
-    pub url: String,
-    pub color: String,
+/// Caps request bodies at `max_body_bytes`, except for asset uploads which get
+/// `max_upload_bytes`
+async fn limit_body(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let config = state.config.load();
+    let limit = if request.uri().path() == admin::ASSET_PATH {
+        config.max_upload_bytes
+    } else {
+        config.max_body_bytes
+    };
+    // turned away up front when the length is known, and cut off partway
+    // through otherwise. the handler only sees a failed read then, so the 413
+    // is sent from here
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let body = request.map(|body| limited_body(Limited::new(body, limit), exceeded.clone()));
+    let response = next.run(body).await;
+    if exceeded.load(Ordering::Relaxed) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    response
 }
 
-impl Card {
-    fn build_embed_html(&self, public_base: &str) -> String {
-        let qs = serde_urlencoded::to_string(OEmbedArgs {
-            provider_name: self.cta.clone(),
-            provider_url: self.url.clone(),
-            author_name: self.title.clone(),
-            author_url: self.url.clone(),
-        })
-        .unwrap();
-        format!(
-            r#"<!doctype html>
-<html>
-    <head>
-        <link rel="alternate" type="application/json+oembed" href="{public_base}/_/oembed.json?{qs}"/>
-        <meta name="theme-color" content="{}">
-        <script>location.href = "{url}"</script>
-    </head>
-    <body>
-        <noscript>Please navigate to <a href="{url}">{url}</a></noscript>
-    </body>
-</html>
-<!-- hi from site-embed -->"#,
-            self.color,
-            url = self.url,
+/// Turns a size limited body back into the body type handlers take, noting
+/// when it runs over the limit
+fn limited_body(body: Limited<Body>, exceeded: Arc<AtomicBool>) -> Body {
+    Body::wrap_stream(futures::stream::unfold(body, move |mut body| {
+        let exceeded = exceeded.clone();
+        async move {
+            let chunk = body.data().await?;
+            if chunk.as_ref().is_err_and(|err| err.is::<LengthLimitError>()) {
+                exceeded.store(true, Ordering::Relaxed);
+            }
+            Some((chunk, body))
+        }
+    }))
+}
+
+/// Builds the cors layer, or nothing when no origins are allowed. A `*` entry
+/// allows any origin
+fn cors_layer(origins: &[String]) -> eyre::Result<Option<CorsLayer>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|v| v == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|v| v.parse().wrap_err_with(|| format!("invalid cors origin {v:?}")))
+                .collect::<eyre::Result<Vec<HeaderValue>>>()?,
         )
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::HEAD]),
+    ))
+}
+
+/// Skips partial responses and content types that are already compressed, on
+/// top of the defaults which skip images and tiny bodies
+fn compression_predicate() -> impl Predicate {
+    DefaultPredicate::new()
+        .and(|status: StatusCode, _, _: &HeaderMap, _: &Extensions| status != StatusCode::PARTIAL_CONTENT)
+        .and(NotForContentType::const_new("font/woff"))
+        .and(NotForContentType::const_new("video/"))
+        .and(NotForContentType::const_new("audio/"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/gzip"))
+}
+
+/// A load error handed out to every request that was waiting on the load
+#[derive(Debug)]
+struct SharedError(Arc<eyre::Report>);
+
+impl Display for SharedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("failed to load entry")
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct OEmbedArgs {
-    provider_name: String,
-    provider_url: String,
-    author_name: String,
-    author_url: String,
+impl Error for SharedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref().as_ref())
+    }
 }
 
-#[debug_handler]
-async fn handle_oembed(Query(query): Query<OEmbedArgs>) -> impl IntoResponse {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(Body::from(serde_json::to_string(&query).unwrap()))
-        .unwrap()
+/// Bumps a click counter in the background, so the redirect doesn't wait on
+/// the write and a failed one only loses the count
+fn count_click(store: Store, key: String) {
+    tokio::spawn(async move {
+        let result = async {
+            let mut redis = store.get().await?;
+            telemetry::redis_call("incr_clicks", redis.incr::<_, _, ()>(&key, 1)).await?;
+            eyre::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            warn!("failed to count click for {key:?}: {err:?}");
+        }
+    });
+}
+
+/// How much of a streamed asset is read from redis at a time
+const STREAM_CHUNK_BYTES: usize = 256 * 1024;
+/// Smallest streaming threshold, which leaves room for any mime type
+const MIN_STREAM_ASSETS_OVER: u64 = 1024;
+
+/// Loads whatever is stored for a path from redis. `site` is the host part of
+/// the keys, empty unless `host_keys` is on
+#[instrument(skip(redis, config))]
+async fn load_entry(redis: &mut StoreConnection<'_>, site: &str, path: &str, config: &Config) -> eyre::Result<CacheEntry> {
+    let key = config.asset_key(site, path);
+    let card_key = config.card_key(site, path);
+    let modified_key = config.modified_key(site, path);
+    let get_asset = match config.stream_assets_over {
+        // reading one byte past the limit is enough to tell if it's over
+        Some(limit) => redis::Cmd::getrange(&key, 0, limit as isize),
+        None => redis::Cmd::get(&key),
+    };
+    let (asset, card, modified) = if redis.is_cluster() {
+        // the keys hash to different slots, so they can't be sent together
+        let asset = telemetry::redis_call("get_asset", get_asset.query_async::<_, Option<Vec<u8>>>(redis)).await?;
+        if asset.as_ref().is_some_and(|v| !v.is_empty()) {
+            let modified = telemetry::redis_call("get_modified", redis.get::<_, Option<u64>>(&modified_key)).await?;
+            (asset, None, modified)
+        } else {
+            let card = telemetry::redis_call("get_card", redis.get::<_, Option<String>>(&card_key)).await?;
+            (None, card, None)
+        }
+    } else {
+        // everything a path could have comes back in one round trip
+        telemetry::redis_call(
+            "get_entry",
+            redis::pipe()
+                .add_command(get_asset)
+                .get(&card_key)
+                .get(&modified_key)
+                .query_async::<_, (Option<Vec<u8>>, Option<String>, Option<u64>)>(redis),
+        )
+        .await?
+    };
+    // a missing key reads as an empty range
+    let asset = asset.filter(|v| !v.is_empty());
+    let streamed_len = match (&asset, config.stream_assets_over) {
+        (Some(head), Some(limit)) if head.len() as u64 > limit => {
+            Some(telemetry::redis_call("get_asset_len", redis.strlen::<_, usize>(&key)).await?)
+        }
+        _ => None,
+    };
+    Ok(match (asset, card) {
+        (Some(v), _) => {
+            let mut iter = v.splitn(2, |x| *x == b';');
+            let mime = iter.next().wrap_err("asset iterator exhausted before first split")?;
+            iter.next().wrap_err("asset iterator exhausted before body")?;
+            let (mime, body_start) = (String::from_utf8_lossy(mime).to_string(), mime.len() + 1);
+            let last_modified = modified.map(|v| UNIX_EPOCH + Duration::from_secs(v));
+            match streamed_len {
+                Some(len) => CacheEntry::Streamed(Arc::new(StreamedAsset {
+                    key,
+                    mime,
+                    body_start,
+                    len: len.saturating_sub(body_start),
+                    last_modified,
+                })),
+                None => {
+                    // the body keeps sharing the buffer redis filled rather
+                    // than being copied out of it
+                    let body = Bytes::from(v).slice(body_start..);
+                    CacheEntry::Asset(Asset {
+                        mime,
+                        etag: format!("\"{}\"", blake3::hash(&body).to_hex()),
+                        body,
+                        last_modified,
+                    })
+                }
+            }
+        }
+        (None, Some(s)) => match decode_card(path, &s, config) {
+            Ok(card) => CacheEntry::Card(Arc::new(card), Arc::default()),
+            Err(CardRejection::Invalid(err)) => return Err(err.into()),
+            Err(rejection) => {
+                warn!("card {path} {rejection}");
+                CacheEntry::Empty
+            }
+        },
+        (None, None) => CacheEntry::Empty,
+    })
+}
+
+/// Why a stored card can't be served
+enum CardRejection {
+    Invalid(serde_json::Error),
+    DisallowedHost(String),
+}
+
+impl Display for CardRejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(err) => write!(f, "isn't a valid card: {err}"),
+            Self::DisallowedHost(url) => write!(f, "redirects to {url:?}, which isn't an allowed host"),
+        }
+    }
+}
+
+/// Parses a stored card, falling back to defaults for anything that can be
+/// fixed up
+fn decode_card(path: &str, stored: &str, config: &Config) -> Result<Card, CardRejection> {
+    let mut card: Card = serde_json::from_str(stored).map_err(CardRejection::Invalid)?;
+    if !is_valid_color(&card.color) {
+        warn!(
+            "card {path} has invalid color {:?}, falling back to {:?}",
+            card.color, config.default_color
+        );
+        card.color = config.default_color.clone();
+    }
+    if let Some(color_dark) = card.color_dark.take_if(|v| !is_valid_color(v)) {
+        warn!(
+            "card {path} has invalid dark color {color_dark:?}, using {:?} for both",
+            card.color
+        );
+    }
+    if let Some(field) = card.missing_field() {
+        warn!("card {path} has type {:?} but no {field}, falling back to link", card.kind);
+        card.kind = OEmbedType::Link;
+    }
+    if !config.allows_redirect(&card.url) {
+        return Err(CardRejection::DisallowedHost(card.url));
+    }
+    Ok(card)
+}
+
+/// Preloads up to `warm_cache_max` entries so a fresh instance doesn't send
+/// every request to redis at once
+async fn warm_cache(store: &Store, cache: &Cache<String, Cached>, config: &Config) -> eyre::Result<()> {
+    // the site is scanned for like any other part of the key
+    let site_pattern = if config.host_keys { "*:" } else { "" };
+    let mut entries = Vec::new();
+    for mut node in store.primaries().await? {
+        for template in [&config.asset_key_template, &config.card_key_template] {
+            let mut iter = node
+                .scan_match::<_, String>(format!("{}{site_pattern}{}", config.key_prefix, template.render("*")))
+                .await?;
+            while let Some(key) = iter.next_item().await {
+                if entries.len() >= config.warm_cache_max {
+                    break;
+                }
+                let Some(key) = key.strip_prefix(&config.key_prefix) else {
+                    continue;
+                };
+                let (site, key) = if config.host_keys {
+                    let Some(split) = split_site(key) else {
+                        continue;
+                    };
+                    split
+                } else {
+                    ("", key)
+                };
+                let Some(path) = template.extract(key) else {
+                    continue;
+                };
+                if !entries.iter().any(|(s, p)| s == site && p == path) {
+                    entries.push((site.to_string(), path.to_string()));
+                }
+            }
+        }
+    }
+
+    let mut redis = store.get().await?;
+
+    for (site, path) in &entries {
+        let entry = load_entry(&mut redis, site, path, config).await?;
+        cache.insert(format!("{site}{path}"), Cached::new(entry)).await;
+    }
+    info!("warmed cache with {} entries", entries.len());
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Config {
+    /// A `redis://` or `rediss://` url, or
+    /// `redis+cluster://host:port,host:port` (`rediss+cluster://` with tls)
+    /// for a cluster
+    pub database_url: String,
+    /// PEM certificate to trust instead of the system roots for tls connections
+    #[serde(default)]
+    pub redis_ca_file: Option<PathBuf>,
+    /// Skips tls certificate verification, only meant for development
+    #[serde(default)]
+    pub redis_tls_insecure: bool,
+    /// Name of the master to ask `sentinel_addresses` for. the host in
+    /// `database_url` is ignored when set, leaving only its credentials and db
+    #[serde(default)]
+    pub sentinel_master: Option<String>,
+    #[serde(default)]
+    pub sentinel_addresses: Vec<String>,
+    /// Most connections the pool keeps open. a cluster instead multiplexes
+    /// over one connection per node, so the pool settings don't apply to it
+    #[serde(default = "default_pool_max_size")]
+    pub pool_max_size: NonZeroU32,
+    #[serde(default)]
+    pub pool_min_idle: Option<u32>,
+    /// How long a request waits for a free connection before failing
+    #[serde(default = "default_pool_timeout_seconds")]
+    pub pool_timeout_seconds: NonZeroU64,
+    /// Longest a single redis command may take before the request fails
+    #[serde(default = "default_redis_timeout_ms")]
+    pub redis_timeout_ms: NonZeroU64,
+    /// Longest a request may take in total before it's answered with a 504
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: NonZeroU64,
+    /// Same for asset uploads, which can take a while to arrive
+    #[serde(default = "default_upload_timeout_ms")]
+    pub upload_timeout_ms: NonZeroU64,
+    /// How many times to retry reaching redis on startup before giving up
+    #[serde(default = "default_startup_retries")]
+    pub startup_retries: u32,
+    /// An ipv6 address like `[::]:8080` takes ipv4 connections too, as
+    /// mapped addresses, where the os supports it
+    #[serde(default)]
+    pub listen_on: Option<SocketAddr>,
+    /// Keeps an ipv6 `listen_on` to ipv6 connections only
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// Unix socket path to listen on instead of `listen_on`
+    #[serde(default)]
+    pub listen_unix: Option<PathBuf>,
+    #[serde(default = "default_http2_keep_alive_seconds")]
+    pub http2_keep_alive_seconds: NonZeroU64,
+    /// How long shutdown waits for in-flight requests before dropping them
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: NonZeroU64,
+    /// PEM certificate chain to serve https with, along with `tls_key`
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// Sends Strict-Transport-Security with this max-age. Needs tls
+    #[serde(default)]
+    pub hsts_max_age_seconds: Option<u64>,
+    #[serde(default)]
+    pub hsts_include_subdomains: bool,
+    /// Expect a PROXY protocol header on every connection and take the client
+    /// address from it. Connections without one are dropped, so only enable
+    /// this behind a proxy that sends it
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Proxies whose `X-Forwarded-For` is believed, as ips or cidr ranges.
+    /// Requests over `listen_unix` always are
+    #[serde(default)]
+    pub trusted_proxies: Vec<ProxyRange>,
+    /// Requests each client ip may make per `rate_limit_window_seconds`,
+    /// unlimited when unset
+    #[serde(default)]
+    pub rate_limit: Option<NonZeroU32>,
+    #[serde(default = "default_rate_limit_window_seconds")]
+    pub rate_limit_window_seconds: NonZeroU64,
+    /// Largest request body accepted. Only admin routes read them, so this
+    /// stays small
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Largest asset accepted by the upload endpoint
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: usize,
+    pub public_base: String,
+    /// Builds `public_base` from each request's scheme and host instead, for
+    /// serving several domains
+    #[serde(default)]
+    pub public_base_from_host: bool,
+    #[serde(default)]
+    pub redirect_status: RedirectStatus,
+    /// Referrer-Policy on card redirects, so the target doesn't learn the
+    /// card path. Empty to leave it off
+    #[serde(default = "default_redirect_referrer_policy")]
+    pub redirect_referrer_policy: String,
+    /// Carries the query string of the request over to the redirect target
+    #[serde(default)]
+    pub redirect_preserve_query: bool,
+    /// Query parameters added to every redirect target, like utm tags
+    #[serde(default)]
+    pub redirect_append_query: BTreeMap<String, String>,
+    /// Counts browser redirects for each card under `clicks:{path}`, at the
+    /// cost of a redis write per redirect
+    #[serde(default)]
+    pub track_clicks: bool,
+    /// Counts cache hits and misses for each stored path in memory, for
+    /// `/_/analytics`
+    #[serde(default)]
+    pub track_path_stats: bool,
+    #[serde(default = "default_color")]
+    pub default_color: String,
+    #[serde(default = "default_cache_idle_seconds")]
+    pub cache_idle_seconds: NonZeroU64,
+    #[serde(default)]
+    pub cache_ttl_seconds: Option<NonZeroU64>,
+    /// Approximate upper bound on cache memory. Empty entries weigh nothing and
+    /// don't count towards it
+    #[serde(default)]
+    pub cache_max_bytes: Option<u64>,
+    /// Assets bigger than this many bytes are streamed from redis on every
+    /// request instead of being held in memory. Only their metadata is cached
+    #[serde(default)]
+    pub stream_assets_over: Option<u64>,
+    #[serde(default)]
+    pub asset_cache_seconds: Option<NonZeroU64>,
+    #[serde(default)]
+    pub card_cache_seconds: Option<NonZeroU64>,
+    #[serde(default)]
+    pub negative_cache_seconds: Option<NonZeroU64>,
+    #[serde(default)]
+    pub cors_allow_origins: Vec<String>,
+    #[serde(default = "default_asset_cache_control")]
+    pub asset_cache_control: String,
+    #[serde(default)]
+    pub card_cache_control: Option<String>,
+    /// Includes the full error report in 500 and 503 pages, for debugging
+    #[serde(default)]
+    pub show_error_details: bool,
+    /// File served at `/favicon.ico`, which is empty otherwise
+    #[serde(default)]
+    pub favicon: Option<PathBuf>,
+    /// Looks up `/favicon.ico` like any other path when `favicon` isn't set,
+    /// for icons uploaded as an asset
+    #[serde(default)]
+    pub favicon_from_assets: bool,
+    /// Contents of `/robots.txt`
+    #[serde(default = "default_robots_txt")]
+    pub robots_txt: String,
+    /// File to serve at `/robots.txt` instead of `robots_txt`
+    #[serde(default)]
+    pub robots_txt_file: Option<PathBuf>,
+    /// Html served for missing paths. Plain text when neither this nor
+    /// `not_found_file` is set
+    #[serde(default)]
+    pub not_found_template: Option<String>,
+    #[serde(default)]
+    pub not_found_file: Option<PathBuf>,
+    /// Content-Security-Policy sent with embeds, where `{script_hash}` allows
+    /// the inline redirect script. Empty to leave it off
+    #[serde(default = "default_embed_csp")]
+    pub embed_csp: String,
+    /// Also redirects browsers with a meta refresh, for when the script can't
+    /// run
+    #[serde(default)]
+    pub embed_meta_refresh: bool,
+    /// Adds nosniff, frame and referrer headers to responses. Turn off when
+    /// the proxy in front sets them
+    #[serde(default = "default_true")]
+    pub security_headers: bool,
+    /// User agent substrings, matched ignoring case, of the link preview
+    /// crawlers that get the embed instead of a redirect
+    #[serde(default = "default_crawler_user_agents")]
+    pub crawler_user_agents: Vec<String>,
+    /// Hosts cards may redirect to. Cards pointing anywhere else are treated
+    /// as missing
+    #[serde(default)]
+    pub allowed_redirect_hosts: Option<Vec<String>>,
+    /// Secret for signed urls. When set, every path needs a `sig` query
+    /// parameter with the hmac-sha256 of the path, minus its leading slash, in
+    /// url-safe base64 without padding. Links with an `exp` timestamp sign
+    /// `{path}?exp={exp}` instead
+    #[serde(default)]
+    pub url_signing_key: Option<String>,
+    /// Prepended to every redis key, to namespace a shared instance
+    #[serde(default)]
+    pub key_prefix: String,
+    /// Serves several sites from one instance by putting the request host in
+    /// every key, after the prefix, as in `example.com:card:{path}`
+    #[serde(default)]
+    pub host_keys: bool,
+    /// How paths with a trailing slash are looked up, `trim` by default
+    #[serde(default)]
+    pub trailing_slash: TrailingSlash,
+    /// Lowercases paths before looking them up, so everything has to be
+    /// stored under lowercase paths
+    #[serde(default)]
+    pub case_insensitive_paths: bool,
+    /// Short paths served as if the longer path they map to was requested
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    #[serde(default = "default_asset_key_template")]
+    pub asset_key_template: KeyTemplate,
+    #[serde(default = "default_card_key_template")]
+    pub card_key_template: KeyTemplate,
+    #[serde(default)]
+    pub warm_cache: bool,
+    #[serde(default = "default_warm_cache_max")]
+    pub warm_cache_max: usize,
+    #[serde(default = "default_invalidations_channel")]
+    pub invalidations_channel: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default = "default_true")]
+    pub access_log: bool,
+    /// Grpc endpoint to export traces to, requires the `otel` feature
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Serves prometheus metrics on `/_/metrics`
+    #[serde(default)]
+    pub enable_metrics: bool,
+    /// Bearer token for the `/_/` admin routes
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Basic auth credentials for the admin routes, usable alongside or
+    /// instead of `admin_token`
+    #[serde(default)]
+    pub admin_user: Option<String>,
+    #[serde(default)]
+    pub admin_password: Option<String>,
+    /// Key sent in `X-API-Key` for the admin routes, meant for deploy scripts
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Serves the admin routes to anyone, for local development. Otherwise
+    /// they're disabled unless one of the credentials above is set
+    #[serde(default)]
+    pub admin_open: bool,
+}
+
+impl Config {
+    /// Catches combinations of settings that can't work together
+    fn validate(&self) -> eyre::Result<()> {
+        store::validate_urls(self)?;
+        if !is_absolute_url(&self.public_base) {
+            eyre::bail!("public_base must be an http or https url, got {:?}", self.public_base);
+        }
+        if self.public_base.ends_with('/') {
+            // paths get appended with their own leading slash
+            eyre::bail!("public_base shouldn't end with a slash, got {:?}", self.public_base);
+        }
+        if !is_valid_color(&self.default_color) {
+            eyre::bail!("default_color {:?} isn't a css color", self.default_color);
+        }
+        // sent as they are, so a bad one would fail every response using it
+        for (key, value) in [
+            ("redirect_referrer_policy", Some(&self.redirect_referrer_policy)),
+            ("asset_cache_control", Some(&self.asset_cache_control)),
+            ("card_cache_control", self.card_cache_control.as_ref()),
+            ("embed_csp", Some(&self.embed_csp)),
+        ] {
+            if let Some(value) = value.filter(|v| HeaderValue::from_str(v).is_err()) {
+                eyre::bail!("{key} {value:?} isn't a valid header value");
+            }
+        }
+        if self.pool_min_idle.is_some_and(|v| v > self.pool_max_size.get()) {
+            eyre::bail!("pool_min_idle can't be more than pool_max_size");
+        }
+        for (key, path) in [
+            ("redis_ca_file", &self.redis_ca_file),
+            ("tls_cert", &self.tls_cert),
+            ("tls_key", &self.tls_key),
+        ] {
+            if let Some(path) = path.as_ref().filter(|v| !v.is_file()) {
+                eyre::bail!("{key} {} doesn't exist", path.display());
+            }
+        }
+        if let Some(ttl) = self.cache_ttl_seconds {
+            for (key, seconds) in [
+                ("asset_cache_seconds", self.asset_cache_seconds),
+                ("card_cache_seconds", self.card_cache_seconds),
+                ("negative_cache_seconds", self.negative_cache_seconds),
+            ] {
+                if seconds.is_some_and(|v| v > ttl) {
+                    eyre::bail!("{key} is longer than cache_ttl_seconds, which would always cut it short");
+                }
+            }
+        }
+        if self.listen_on.is_some() == self.listen_unix.is_some() {
+            eyre::bail!("exactly one of listen_on and listen_unix must be set");
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            eyre::bail!("tls_cert and tls_key must be set together");
+        }
+        if self.tls_cert.is_some() && self.listen_unix.is_some() {
+            eyre::bail!("tls is only supported with listen_on");
+        }
+        if self.hsts_max_age_seconds.is_some() && self.tls_cert.is_none() {
+            eyre::bail!("hsts is only sent over tls, so needs tls_cert and tls_key");
+        }
+        if self.ipv6_only && !self.listen_on.is_some_and(|v| v.is_ipv6()) {
+            eyre::bail!("ipv6_only only applies to an ipv6 listen_on");
+        }
+        if self.proxy_protocol && self.listen_unix.is_some() {
+            eyre::bail!("proxy_protocol is only supported with listen_on");
+        }
+        if self.url_signing_key.as_ref().is_some_and(|v| v.is_empty()) {
+            eyre::bail!("url_signing_key can't be empty");
+        }
+        if self.not_found_template.is_some() && self.not_found_file.is_some() {
+            eyre::bail!("only one of not_found_template and not_found_file can be set");
+        }
+        if self.admin_user.is_some() != self.admin_password.is_some() {
+            eyre::bail!("admin_user and admin_password must be set together");
+        }
+        if self.admin_open && (self.admin_token.is_some() || self.admin_user.is_some() || self.api_key.is_some()) {
+            eyre::bail!("admin_open turns off admin authentication, so it can't be set along with credentials");
+        }
+        if self.stream_assets_over.is_some_and(|v| v < MIN_STREAM_ASSETS_OVER) {
+            // the mime type is found in what's read up to the limit
+            eyre::bail!("stream_assets_over has to be at least {MIN_STREAM_ASSETS_OVER}");
+        }
+        Ok(())
+    }
+
+    /// Shape a path is looked up in, going by `trailing_slash` and
+    /// `case_insensitive_paths`
+    fn normalize_path(&self, path: &str) -> String {
+        let path = match self.trailing_slash {
+            TrailingSlash::Strict => path.trim_start_matches('/'),
+            TrailingSlash::Trim | TrailingSlash::Redirect => path.trim_matches('/'),
+        };
+        if self.case_insensitive_paths {
+            path.to_lowercase()
+        } else {
+            path.to_string()
+        }
+    }
+
+    /// Puts aliases in the shape of the paths they get matched against
+    fn normalize_aliases(&mut self) -> eyre::Result<()> {
+        for (alias, target) in std::mem::take(&mut self.aliases) {
+            let key = self.normalize_path(&alias);
+            if self.aliases.insert(key, target.trim_matches('/').to_string()).is_some() {
+                eyre::bail!("alias {alias:?} is the same path as another alias");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any way of authenticating to the admin routes is configured
+    fn admin_enabled(&self) -> bool {
+        self.admin_open || self.admin_token.is_some() || self.admin_user.is_some() || self.api_key.is_some()
+    }
+
+    /// Paths stay on this host, so only urls naming one are checked
+    fn allows_redirect(&self, url: &str) -> bool {
+        let Some(allowed) = &self.allowed_redirect_hosts else {
+            return true;
+        };
+        // browsers drop these anywhere in a url, so `/\t/host` is `//host` to them
+        let url = url.replace(['\t', '\r', '\n'], "");
+        if is_local_path(&url) {
+            return true;
+        }
+        url.parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(|host| allowed.iter().any(|v| v.eq_ignore_ascii_case(host))))
+            .unwrap_or(false)
+    }
+
+    fn is_crawler(&self, user_agent: &str) -> bool {
+        let user_agent = user_agent.to_lowercase();
+        self.crawler_user_agents
+            .iter()
+            .any(|crawler| user_agent.contains(&crawler.to_lowercase()))
+    }
+
+    fn asset_key(&self, site: &str, path: &str) -> String {
+        format!("{}{site}{}", self.key_prefix, self.asset_key_template.render(path))
+    }
+
+    fn card_key(&self, site: &str, path: &str) -> String {
+        format!("{}{site}{}", self.key_prefix, self.card_key_template.render(path))
+    }
+
+    fn modified_key(&self, site: &str, path: &str) -> String {
+        format!("{}{site}modified:{path}", self.key_prefix)
+    }
+
+    fn clicks_key(&self, site: &str, path: &str) -> String {
+        format!("{}{site}clicks:{path}", self.key_prefix)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum TrailingSlash {
+    /// `/a/` and `/a` are the same path
+    #[default]
+    Trim,
+    /// `/a/` is looked up as `a/`, separately from `/a`
+    Strict,
+    /// `/a/` is permanently redirected to `/a`
+    Redirect,
+}
+
+/// Redis key shape with a single `{path}` placeholder
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct KeyTemplate {
+    before: String,
+    after: String,
+}
+
+impl KeyTemplate {
+    fn render(&self, path: &str) -> String {
+        format!("{}{path}{}", self.before, self.after)
+    }
+
+    /// Pulls the path back out of a rendered key
+    fn extract<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(&self.before)?.strip_suffix(&self.after)
+    }
+}
+
+impl TryFrom<String> for KeyTemplate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.split_once("{path}") {
+            Some((before, after)) if !after.contains("{path}") => Ok(Self {
+                before: before.to_string(),
+                after: after.to_string(),
+            }),
+            _ => Err(format!("key template {value:?} must contain {{path}} exactly once")),
+        }
+    }
+}
+
+fn default_asset_key_template() -> KeyTemplate {
+    KeyTemplate::try_from("asset:{path}".to_string()).unwrap()
+}
+
+fn default_card_key_template() -> KeyTemplate {
+    KeyTemplate::try_from("card:{path}".to_string()).unwrap()
+}
+
+/// Short enough to finish within the `kill_timeout` in fly.toml
+fn default_shutdown_timeout_seconds() -> NonZeroU64 {
+    NonZeroU64::new(4).unwrap()
+}
+
+fn default_http2_keep_alive_seconds() -> NonZeroU64 {
+    NonZeroU64::new(30).unwrap()
+}
+
+fn default_max_body_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_max_upload_bytes() -> usize {
+    32 * 1024 * 1024
+}
+
+fn default_rate_limit_window_seconds() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+fn default_pool_max_size() -> NonZeroU32 {
+    NonZeroU32::new(10).unwrap()
+}
+
+fn default_pool_timeout_seconds() -> NonZeroU64 {
+    NonZeroU64::new(30).unwrap()
+}
+
+fn default_request_timeout_ms() -> NonZeroU64 {
+    NonZeroU64::new(10_000).unwrap()
+}
+
+fn default_upload_timeout_ms() -> NonZeroU64 {
+    NonZeroU64::new(120_000).unwrap()
+}
+
+fn default_redis_timeout_ms() -> NonZeroU64 {
+    NonZeroU64::new(2000).unwrap()
+}
+
+fn default_startup_retries() -> u32 {
+    8
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_redirect_referrer_policy() -> String {
+    "no-referrer".to_string()
+}
+
+fn default_robots_txt() -> String {
+    "User-agent: *\nDisallow:\n".to_string()
+}
+
+fn default_embed_csp() -> String {
+    "default-src 'none'; script-src {script_hash}; base-uri 'none'; form-action 'none'".to_string()
+}
+
+fn default_crawler_user_agents() -> Vec<String> {
+    [
+        "Discordbot",
+        "Slackbot",
+        "TelegramBot",
+        "Twitterbot",
+        "facebookexternalhit",
+        "WhatsApp",
+        "LinkedInBot",
+        "Mastodon",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_color() -> String {
+    "#000000".to_string()
+}
+
+fn default_asset_cache_control() -> String {
+    "public, max-age=3600".to_string()
+}
+
+fn default_warm_cache_max() -> usize {
+    1000
+}
+
+fn default_invalidations_channel() -> String {
+    "invalidations".to_string()
+}
+
+fn default_cache_idle_seconds() -> NonZeroU64 {
+    NonZeroU64::new(60 * 60).unwrap()
+}
+
+/// Status code used when redirecting browsers to a card's url
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "u16")]
+struct RedirectStatus(StatusCode);
+
+impl Default for RedirectStatus {
+    fn default() -> Self {
+        Self(StatusCode::PERMANENT_REDIRECT)
+    }
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            301 | 302 | 307 | 308 => Ok(Self(StatusCode::from_u16(value).unwrap())),
+            _ => Err(format!(
+                "unsupported redirect status {value}, expected one of 301, 302, 307, or 308"
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Cached {
+    pub entry: CacheEntry,
+    pub inserted_at: Instant,
+}
+
+impl Cached {
+    fn new(entry: CacheEntry) -> Self {
+        Self {
+            entry,
+            inserted_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CacheEntry {
+    Empty,
+    Asset(Asset),
+    Card(Arc<Card>, Arc<RenderedEmbeds>),
+    Streamed(Arc<StreamedAsset>),
+}
+
+#[derive(Clone)]
+struct Asset {
+    pub mime: String,
+    /// Cheap to clone, so cache hits don't copy it
+    pub body: Bytes,
+    /// Strong etag derived from the body, already quoted
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Asset {
+    fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        is_not_modified(Some(&self.etag), self.last_modified, headers)
+    }
+
+    fn requested_range(&self, headers: &HeaderMap) -> Option<ByteRange> {
+        requested_range(Some(&self.etag), self.last_modified, self.body.len(), headers)
+    }
+}
+
+/// Most renderings kept per card. There's only ever one unless
+/// `public_base_from_host` is on
+const MAX_RENDERED_EMBEDS: usize = 4;
+
+/// Rough size of a rendered embed: the markup around the card, plus each of
+/// its fields showing up several times, some of them escaped
+const EMBED_OVERHEAD_BYTES: usize = 1024;
+const EMBED_FIELD_REPEATS: usize = 8;
+
+/// Embed html already rendered for a card, along with the public base it was
+/// rendered for. Being part of the card's entry, it goes whenever the card
+/// gets invalidated
+#[derive(Default)]
+struct RenderedEmbeds(Mutex<Vec<(String, Bytes)>>);
+
+impl RenderedEmbeds {
+    fn get_or_render(&self, public_base: &str, render: impl FnOnce(&str) -> String) -> Bytes {
+        let mut rendered = self.0.lock().unwrap();
+        if let Some((_, html)) = rendered.iter().find(|(base, _)| base == public_base) {
+            return html.clone();
+        }
+        let html = Bytes::from(render(public_base));
+        if rendered.len() >= MAX_RENDERED_EMBEDS {
+            rendered.remove(0);
+        }
+        rendered.push((public_base.to_string(), html.clone()));
+        html
+    }
+}
+
+/// Asset too big to keep in memory, which gets read out of redis in chunks
+/// as it's sent. There's no etag since that would mean hashing the whole body
+struct StreamedAsset {
+    pub key: String,
+    pub mime: String,
+    /// Offset of the body in the stored value, past the mime type
+    pub body_start: usize,
+    pub len: usize,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl StreamedAsset {
+    fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        is_not_modified(None, self.last_modified, headers)
+    }
+
+    fn requested_range(&self, headers: &HeaderMap) -> Option<ByteRange> {
+        requested_range(None, self.last_modified, self.len, headers)
+    }
+
+    /// Streams `range` of the body, fetching one chunk at a time so only a
+    /// chunk is ever held in memory
+    fn body(self: Arc<Self>, store: Store, range: std::ops::Range<usize>) -> Body {
+        let chunks = futures::stream::try_unfold(range, move |range| {
+            let (asset, store) = (self.clone(), store.clone());
+            async move {
+                if range.is_empty() {
+                    return eyre::Ok(None);
+                }
+                let end = range.end.min(range.start + STREAM_CHUNK_BYTES);
+                let mut redis = store.get().await?;
+                let chunk = telemetry::redis_call(
+                    "get_asset_chunk",
+                    redis.getrange::<_, Vec<u8>>(
+                        &asset.key,
+                        (asset.body_start + range.start) as isize,
+                        (asset.body_start + end - 1) as isize,
+                    ),
+                )
+                .await?;
+                // the length is already sent, so a shorter value can't be
+                // papered over
+                if chunk.len() != end - range.start {
+                    eyre::bail!("asset {} changed while being streamed", asset.key);
+                }
+                Ok(Some((Bytes::from(chunk), end..range.end)))
+            }
+        });
+        Body::wrap_stream(chunks.inspect_err(|err| warn!("failed to stream asset: {err:?}")))
+    }
+}
+
+/// Evaluates conditional request headers, with `If-None-Match` taking
+/// precedence over `If-Modified-Since` when both are sent
+fn is_not_modified(etag: Option<&str>, last_modified: Option<SystemTime>, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = headers.get("If-None-Match") {
+        return match (if_none_match.to_str(), etag) {
+            (Ok(if_none_match), Some(etag)) => etag_matches(if_none_match, etag),
+            _ => false,
+        };
+    }
+
+    match (
+        last_modified,
+        headers
+            .get("If-Modified-Since")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok()),
+    ) {
+        // http dates only have second precision
+        (Some(last_modified), Some(since)) => {
+            last_modified.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0)
+                <= since.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0)
+        }
+        _ => false,
+    }
+}
+
+/// Works out which range the client asked for, if any. Ranges are ignored
+/// when `If-Range` doesn't match, and multiple ranges aren't supported so
+/// those get the full body too
+fn requested_range(etag: Option<&str>, last_modified: Option<SystemTime>, len: usize, headers: &HeaderMap) -> Option<ByteRange> {
+    if let Some(if_range) = headers.get("If-Range") {
+        let if_range = if_range.to_str().ok()?;
+        let matches_date = last_modified.map(|v| httpdate::fmt_http_date(v) == if_range).unwrap_or(false);
+        if etag != Some(if_range) && !matches_date {
+            return None;
+        }
+    }
+
+    parse_range(headers.get("Range")?.to_str().ok()?, len)
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Satisfiable(std::ops::Range<usize>),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range against a body of `len` bytes
+fn parse_range(header: &str, len: usize) -> Option<ByteRange> {
+    let spec = header.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<usize>().ok()?;
+            len.saturating_sub(suffix)..len
+        }
+        (start, "") => start.parse::<usize>().ok()?..len,
+        (start, end) => {
+            let start = start.parse::<usize>().ok()?;
+            let end = end.parse::<usize>().ok()?;
+            if end < start {
+                return None;
+            }
+            start..(end + 1).min(len)
+        }
+    };
+
+    Some(if range.start < len && !range.is_empty() {
+        ByteRange::Satisfiable(range)
+    } else {
+        ByteRange::Unsatisfiable
+    })
+}
+
+/// Per-entry expiration, layered on top of the cache-wide idle and live
+/// timeouts. Read from the current config so a reload applies to new entries
+struct CacheExpiry(Arc<ArcSwap<Config>>);
+
+impl CacheExpiry {
+    fn expire_after(&self, value: &Cached) -> Option<Duration> {
+        let config = self.0.load();
+        match value.entry {
+            CacheEntry::Empty => config.negative_cache_seconds,
+            CacheEntry::Asset(_) | CacheEntry::Streamed(_) => config.asset_cache_seconds,
+            CacheEntry::Card(..) => config.card_cache_seconds,
+        }
+        .map(|v| Duration::from_secs(v.get()))
+    }
+}
+
+impl Expiry<String, Cached> for CacheExpiry {
+    fn expire_after_create(&self, _key: &String, value: &Cached, _current_time: Instant) -> Option<Duration> {
+        self.expire_after(value)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &Cached,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after(value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Card {
+    pub title: String,
+    pub cta: String,
+    pub url: String,
+    pub color: String,
+    /// Color for dark themes, when it should differ from `color`
+    #[serde(default)]
+    pub color_dark: Option<String>,
+    #[serde(default)]
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub image_width: Option<u32>,
+    #[serde(default)]
+    pub image_height: Option<u32>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Dimensions declared to oembed consumers
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Shape of the oembed response
+    #[serde(default, rename = "type")]
+    pub kind: OEmbedType,
+    /// Embed markup, required for the video and rich types
+    #[serde(default)]
+    pub html: Option<String>,
+}
+
+impl Card {
+    /// Bytes taken up by the card's strings
+    fn weight(&self) -> usize {
+        [&self.title, &self.cta, &self.url, &self.color]
+            .iter()
+            .map(|v| v.len())
+            .sum::<usize>()
+            + [&self.color_dark, &self.image_url, &self.description, &self.html]
+                .iter()
+                .map(|v| v.as_ref().map_or(0, String::len))
+                .sum::<usize>()
+    }
+
+    /// Field the card's type needs but doesn't have
+    fn missing_field(&self) -> Option<&'static str> {
+        match self.kind {
+            OEmbedType::Video | OEmbedType::Rich => self.html.is_none().then_some("html"),
+            OEmbedType::Photo => self.image_url.is_none().then_some("image_url"),
+            OEmbedType::Link => None,
+        }
+    }
+
+    /// Strict version of the checks done on load, for cards that haven't been
+    /// stored yet and so can still be turned away
+    fn validate(&self, config: &Config) -> Result<(), String> {
+        if !is_valid_color(&self.color) {
+            return Err(format!("color {:?} isn't a css color", self.color));
+        }
+        if let Some(color_dark) = self.color_dark.as_ref().filter(|v| !is_valid_color(v)) {
+            return Err(format!("color_dark {color_dark:?} isn't a css color"));
+        }
+        if let Some(field) = self.missing_field() {
+            return Err(format!("type {:?} needs {field}", self.kind));
+        }
+        if !config.allows_redirect(&self.url) {
+            return Err(format!("url {:?} isn't on an allowed host", self.url));
+        }
+        Ok(())
+    }
+
+    fn build_embed_html(&self, config: &Config, public_base: &str) -> String {
+        // discord refuses to load relative image urls, so don't bother emitting them
+        let image_url = self.image_url.as_deref().filter(|v| is_absolute_url(v));
+        let qs = serde_urlencoded::to_string(OEmbedArgs {
+            provider_name: self.cta.clone(),
+            provider_url: self.url.clone(),
+            author_name: self.title.clone(),
+            author_url: self.url.clone(),
+            width: self.width,
+            height: self.height,
+            kind: self.kind,
+            html: matches!(self.kind, OEmbedType::Video | OEmbedType::Rich)
+                .then(|| self.html.clone())
+                .flatten(),
+            url: image_url.filter(|_| self.kind == OEmbedType::Photo).map(String::from),
+            thumbnail_url: image_url.map(String::from),
+            thumbnail_width: image_url.and(self.image_width),
+            thumbnail_height: image_url.and(self.image_height),
+        })
+        .unwrap();
+        let mut meta = String::new();
+        match &self.color_dark {
+            Some(color_dark) => {
+                for (scheme, color) in [("light", &self.color), ("dark", color_dark)] {
+                    write!(
+                        meta,
+                        "\n        <meta name=\"theme-color\" media=\"(prefers-color-scheme: {scheme})\" content=\"{}\">",
+                        escape_html(color)
+                    )
+                    .unwrap();
+                }
+            }
+            None => push_meta(&mut meta, "name", "theme-color", &self.color),
+        }
+        push_meta(&mut meta, "property", "og:title", &self.title);
+        push_meta(&mut meta, "property", "og:url", &self.url);
+        if let Some(description) = &self.description {
+            push_meta(&mut meta, "property", "og:description", description);
+        }
+        push_meta(
+            &mut meta,
+            "name",
+            "twitter:card",
+            if image_url.is_some() {
+                "summary_large_image"
+            } else {
+                "summary"
+            },
+        );
+        push_meta(&mut meta, "name", "twitter:title", &self.title);
+        if let Some(description) = &self.description {
+            push_meta(&mut meta, "name", "twitter:description", description);
+        }
+        if let Some(image_url) = image_url {
+            push_meta(&mut meta, "property", "og:image", image_url);
+            push_meta(&mut meta, "name", "twitter:image", image_url);
+        }
+        let url = escape_html(&self.url);
+        // for browsers that block the script, like ones obeying a strict csp
+        if config.embed_meta_refresh {
+            write!(meta, "\n        <meta http-equiv=\"refresh\" content=\"0;url={url}\">").unwrap();
+        }
+        format!(
+            r#"<!doctype html>
+<html>
+    <head>
+        <link rel="alternate" type="application/json+oembed" href="{}"/>{meta}
+        <script>{}</script>
+    </head>
+    <body>
+        <noscript>Please navigate to <a href="{url}">{url}</a></noscript>
+    </body>
+</html>
+<!-- hi from site-embed -->"#,
+            escape_html(&format!("{public_base}/_/oembed.json?{qs}")),
+            self.redirect_script(),
+        )
+    }
+
+    /// Inline script sending browsers that land on the embed to the target
+    fn redirect_script(&self) -> String {
+        format!("location.href = {}", escape_js_string(&self.url))
+    }
+}
+
+/// Appends a meta tag on its own line, escaping the content
+fn push_meta(buffer: &mut String, attribute: &str, key: &str, content: &str) {
+    write!(
+        buffer,
+        "\n        <meta {attribute}=\"{key}\" content=\"{}\">",
+        escape_html(content)
+    )
+    .unwrap();
+}
+
+/// Checks for a `#rgb`/`#rrggbb` hex color or a named css color
+fn is_valid_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => CSS_COLOR_NAMES.contains(&color.to_ascii_lowercase().as_str()),
+    }
+}
+
+#[rustfmt::skip]
+const CSS_COLOR_NAMES: &[&str] = &[
+    "aliceblue", "antiquewhite", "aqua", "aquamarine", "azure", "beige", "bisque", "black", "blanchedalmond", "blue",
+    "blueviolet", "brown", "burlywood", "cadetblue", "chartreuse", "chocolate", "coral", "cornflowerblue", "cornsilk",
+    "crimson", "cyan", "darkblue", "darkcyan", "darkgoldenrod", "darkgray", "darkgreen", "darkgrey", "darkkhaki", "darkmagenta",
+    "darkolivegreen", "darkorange", "darkorchid", "darkred", "darksalmon", "darkseagreen", "darkslateblue", "darkslategray",
+    "darkslategrey", "darkturquoise", "darkviolet", "deeppink", "deepskyblue", "dimgray", "dimgrey", "dodgerblue", "firebrick",
+    "floralwhite", "forestgreen", "fuchsia", "gainsboro", "ghostwhite", "gold", "goldenrod", "gray", "green", "greenyellow",
+    "grey", "honeydew", "hotpink", "indianred", "indigo", "ivory", "khaki", "lavender", "lavenderblush", "lawngreen",
+    "lemonchiffon", "lightblue", "lightcoral", "lightcyan", "lightgoldenrodyellow", "lightgray", "lightgreen", "lightgrey",
+    "lightpink", "lightsalmon", "lightseagreen", "lightskyblue", "lightslategray", "lightslategrey", "lightsteelblue",
+    "lightyellow", "lime", "limegreen", "linen", "magenta", "maroon", "mediumaquamarine", "mediumblue", "mediumorchid",
+    "mediumpurple", "mediumseagreen", "mediumslateblue", "mediumspringgreen", "mediumturquoise", "mediumvioletred",
+    "midnightblue", "mintcream", "mistyrose", "moccasin", "navajowhite", "navy", "oldlace", "olive", "olivedrab", "orange",
+    "orangered", "orchid", "palegoldenrod", "palegreen", "paleturquoise", "palevioletred", "papayawhip", "peachpuff", "peru",
+    "pink", "plum", "powderblue", "purple", "rebeccapurple", "red", "rosybrown", "royalblue", "saddlebrown", "salmon",
+    "sandybrown", "seagreen", "seashell", "sienna", "silver", "skyblue", "slateblue", "slategray", "slategrey", "snow",
+    "springgreen", "steelblue", "tan", "teal", "thistle", "tomato", "turquoise", "violet", "wheat", "white", "whitesmoke",
+    "yellow", "yellowgreen",
+];
+
+/// Whether a url stays on this host. Browsers read `//host` and `/\host` as
+/// another host
+fn is_local_path(url: &str) -> bool {
+    url.strip_prefix('/').is_some_and(|rest| !rest.starts_with(['/', '\\']))
+}
+
+fn is_absolute_url(url: &str) -> bool {
+    url.strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .map(|rest| !rest.is_empty())
+        .unwrap_or(false)
+}
+
+/// Host a request was made to, lowercased and without its port
+fn request_host(request: &Request<Body>) -> Option<String> {
+    let authority = match request.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => request.headers().get("Host")?.to_str().ok()?.parse::<Authority>().ok()?,
+    };
+    Some(authority.host().to_ascii_lowercase())
+}
+
+/// Base url for links back to the shim. With `public_base_from_host` it's
+/// built from the request, believing forwarded headers only from trusted
+/// proxies
+fn public_base<'a>(request: &Request<Body>, config: &'a Config) -> Cow<'a, str> {
+    if !config.public_base_from_host {
+        return config.public_base.as_str().into();
+    }
+    // the nearest proxy's value is the one that can be relied on
+    let forwarded = |name| {
+        request.extensions().get::<FromTrustedProxy>()?;
+        let value = request.headers().get_all(name).iter().next_back()?.to_str().ok()?;
+        Some(value.rsplit(',').next()?.trim())
+    };
+    let scheme = match forwarded("X-Forwarded-Proto") {
+        Some(scheme @ ("http" | "https")) => scheme,
+        _ if config.tls_cert.is_some() => "https",
+        _ => "http",
+    };
+    let host = forwarded("X-Forwarded-Host")
+        .and_then(|v| v.parse::<Authority>().ok())
+        .or_else(|| request.uri().authority().cloned())
+        .or_else(|| request.headers().get("Host")?.to_str().ok()?.parse().ok());
+    match host {
+        Some(host) => format!("{scheme}://{host}").into(),
+        None => config.public_base.as_str().into(),
+    }
+}
+
+/// Splits the `host:` site off the front of a key, keeping the site's colon.
+/// Ipv6 hosts are bracketed, so their colons aren't mistaken for the end
+fn split_site(key: &str) -> Option<(&str, &str)> {
+    let end = if key.starts_with('[') {
+        key.find("]:")? + 2
+    } else {
+        key.find(':')? + 1
+    };
+    Some(key.split_at(end))
+}
+
+/// Checks the `sig` query parameter against the path, in constant time. A
+/// link with an `exp` unix timestamp is signed as `{path}?exp={exp}` and stops
+/// working after it
+fn is_signed(key: &str, path: &str, query: Option<&str>, now: SystemTime) -> bool {
+    let param = |name| {
+        query
+            .into_iter()
+            .flat_map(|v| v.split('&'))
+            .find_map(|v| v.strip_prefix(name)?.strip_prefix('='))
+    };
+    let Some(Ok(tag)) = param("sig").map(|v| URL_SAFE_NO_PAD.decode(v)) else {
+        return false;
+    };
+    let message = match param("exp") {
+        Some(exp) => {
+            let Ok(expires) = exp.parse::<u64>() else {
+                return false;
+            };
+            if now > UNIX_EPOCH + Duration::from_secs(expires) {
+                return false;
+            }
+            Cow::Owned(format!("{path}?exp={exp}"))
+        }
+        None => Cow::Borrowed(path),
+    };
+    hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()), message.as_bytes(), &tag).is_ok()
+}
+
+fn without_signature(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|v| !v.starts_with("sig=") && !v.starts_with("exp="))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Adds the parameters a url doesn't already have, so ones set on the card or
+/// carried over from the request win
+fn merge_query(url: &str, params: &BTreeMap<String, String>) -> String {
+    let query = url.split('#').next().unwrap_or_default().split_once('?').map(|(_, v)| v);
+    let existing = serde_urlencoded::from_str::<Vec<(String, String)>>(query.unwrap_or_default()).unwrap_or_default();
+    let missing = params
+        .iter()
+        .filter(|(key, _)| !existing.iter().any(|(v, _)| v == *key))
+        .collect::<Vec<_>>();
+    append_query(url, &serde_urlencoded::to_string(missing).unwrap())
+}
+
+/// Adds query parameters to a url, after any it already has and before its
+/// fragment
+fn append_query(url: &str, query: &str) -> String {
+    if query.is_empty() {
+        return url.to_string();
+    }
+    let (base, fragment) = match url.split_once('#') {
+        Some((base, fragment)) => (base, Some(fragment)),
+        None => (url, None),
+    };
+    let separator = if !base.contains('?') {
+        "?"
+    } else if base.ends_with(['?', '&']) {
+        ""
+    } else {
+        "&"
+    };
+    let mut url = format!("{base}{separator}{query}");
+    if let Some(fragment) = fragment {
+        write!(url, "#{fragment}").unwrap();
+    }
+    url
+}
+
+/// Escapes a string for use as html text or a quoted attribute value
+fn escape_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#x27;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// Produces a quoted js string literal which is safe to place inside a script
+/// tag
+fn escape_js_string(input: &str) -> String {
+    serde_json::to_string(input)
+        .unwrap()
+        .replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026")
+}
+
+#[derive(Serialize, Deserialize)]
+struct OEmbedArgs {
+    provider_name: String,
+    provider_url: String,
+    author_name: String,
+    author_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(default, rename = "type")]
+    kind: OEmbedType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum OEmbedType {
+    #[default]
+    Link,
+    Photo,
+    Video,
+    Rich,
+}
+
+/// Browsers ask for this on every visit, so it only goes to redis when
+/// `favicon_from_assets` asks for it
+async fn handle_favicon(State(state): State<AppState>, request: Request<Body>) -> Response {
+    match &state.favicon {
+        Some(favicon) => (
+            [
+                ("Content-Type", favicon.content_type),
+                ("Cache-Control", &state.config.load().asset_cache_control),
+            ],
+            favicon.body.clone(),
+        )
+            .into_response(),
+        None if state.config.load().favicon_from_assets => handle(State(state), request).await.into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn handle_robots_txt(State(state): State<AppState>) -> impl IntoResponse {
+    ([("Content-Type", "text/plain")], state.robots_txt.to_string())
+}
+
+async fn handle_health() -> &'static str {
+    "ok"
+}
+
+/// Upper bound on how long the readiness probe waits for redis
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn handle_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let check = async {
+        let mut redis = state.store.get().await?;
+        redis::cmd("PING").query_async::<_, String>(&mut redis).await?;
+        eyre::Ok(())
+    };
+    match tokio::time::timeout(READY_TIMEOUT, check).await {
+        Ok(Ok(())) => (StatusCode::OK, "ok"),
+        Ok(Err(err)) => {
+            warn!("readiness check failed: {err:?}");
+            (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        }
+        Err(_) => {
+            warn!("readiness check timed out");
+            (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OEmbedFormat {
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// What the oembed endpoint sends back, with the fields every response needs
+#[derive(Serialize)]
+struct OEmbedResponse<'a> {
+    version: &'static str,
+    #[serde(flatten)]
+    args: &'a OEmbedArgs,
+}
+
+#[debug_handler]
+async fn handle_oembed(Query(query): Query<OEmbedArgs>, Query(format): Query<OEmbedFormat>) -> impl IntoResponse {
+    let response = OEmbedResponse {
+        version: "1.0",
+        args: &query,
+    };
+    match format.format.as_deref() {
+        None | Some("json") => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&response).unwrap()))
+            .unwrap(),
+        Some("xml") => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/xml")
+            .body(Body::from(oembed_xml(&response)))
+            .unwrap(),
+        // what the spec asks for on formats it doesn't know
+        Some(_) => Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(Body::from("unsupported format"))
+            .unwrap(),
+    }
+}
+
+/// Writes the same fields as the json response, one element each
+fn oembed_xml(response: &OEmbedResponse<'_>) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?><oembed>"#);
+    if let serde_json::Value::Object(fields) = serde_json::to_value(response).unwrap() {
+        for (name, value) in fields {
+            let value = match value {
+                serde_json::Value::String(v) => v,
+                v => v.to_string(),
+            };
+            write!(xml, "<{name}>{}</{name}>", escape_html(&value)).unwrap();
+        }
+    }
+    xml.push_str("</oembed>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        Figment::new()
+            .merge(Toml::string(&format!(
+                "database_url = \"redis://localhost\"\npublic_base = \"https://shim.example\"\n{extra}"
+            )))
+            .extract()
+            .unwrap()
+    }
+
+    fn card(title: &str, url: &str, color: &str) -> Card {
+        serde_json::from_value(serde_json::json!({
+            "title": title,
+            "cta": "cta",
+            "url": url,
+            "color": color,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn embed_escapes_html() {
+        let nasty = r#"a"b'c<d>e&f"#;
+        let escaped = "a&quot;b&#x27;c&lt;d&gt;e&amp;f";
+        let html =
+            card(nasty, &format!("https://example.com/{nasty}"), nasty).build_embed_html(&config(""), "https://shim.example");
+
+        assert!(html.contains(&format!(r#"<meta name="theme-color" content="{escaped}">"#)));
+        assert!(html.contains(&format!(r#"<meta property="og:title" content="{escaped}">"#)));
+        assert!(html.contains(&format!(
+            r#"<a href="https://example.com/{escaped}">https://example.com/{escaped}</a></noscript>"#
+        )));
+        assert!(!html.contains(nasty));
+    }
+
+    #[test]
+    fn redirect_script_is_one_string_literal() {
+        for url in [r#""};alert(1)//"#, "https://example.com/</script><script>alert(1)</script>"] {
+            let card = card("title", url, "red");
+            let script = card.redirect_script();
+            let literal = script.strip_prefix("location.href = ").unwrap();
+            assert_eq!(serde_json::from_str::<String>(literal).unwrap(), url);
+            assert!(!literal.contains('<'));
+            assert_eq!(url.contains('<'), literal.contains("\\u003c"));
+
+            let html = card.build_embed_html(&config(""), "https://shim.example");
+            assert_eq!(html.matches("</script>").count(), 1);
+            assert!(html.contains(&format!("<script>{script}</script>")));
+        }
+    }
+
+    #[test]
+    fn entries_expire_on_their_schedule() {
+        let expiry = CacheExpiry(Arc::new(ArcSwap::from_pointee(config(
+            "asset_cache_seconds = 10\ncard_cache_seconds = 20\nnegative_cache_seconds = 30",
+        ))));
+        let asset = CacheEntry::Asset(Asset {
+            mime: "text/plain".into(),
+            body: Bytes::from_static(b"hi"),
+            etag: "\"etag\"".into(),
+            last_modified: None,
+        });
+        let streamed = CacheEntry::Streamed(Arc::new(StreamedAsset {
+            key: "asset:big".into(),
+            mime: "text/plain".into(),
+            body_start: 11,
+            len: 4096,
+            last_modified: None,
+        }));
+        let card = CacheEntry::Card(Arc::new(card("title", "https://example.com", "red")), Default::default());
+        for (entry, seconds) in [(CacheEntry::Empty, 30), (asset, 10), (streamed, 10), (card, 20)] {
+            assert_eq!(expiry.expire_after(&Cached::new(entry)), Some(Duration::from_secs(seconds)));
+        }
+
+        // without per-kind settings only the cache-wide timeouts apply
+        let expiry = CacheExpiry(Arc::new(ArcSwap::from_pointee(config(""))));
+        assert_eq!(expiry.expire_after(&Cached::new(CacheEntry::Empty)), None);
+    }
+
+    #[test]
+    fn redirects_off_host_are_checked() {
+        let config = config("allowed_redirect_hosts = [\"example.com\"]");
+        for url in ["/", "/some/path", "https://example.com/x", "https://EXAMPLE.com"] {
+            assert!(config.allows_redirect(url), "{url:?}");
+        }
+        for url in [
+            "//evil.com",
+            "/\\evil.com",
+            "/\t/evil.com",
+            "/\n/evil.com",
+            "/\r\n\\evil.com",
+            "https://evil.com",
+            "https://exam\tple.com.evil.com",
+            "evil.com",
+        ] {
+            assert!(!config.allows_redirect(url), "{url:?}");
+        }
+    }
+
+    #[test]
+    fn byte_ranges() {
+        use ByteRange::*;
+        for (header, expected) in [
+            ("bytes=2-4", Some(Satisfiable(2..5))),
+            ("bytes=5-100", Some(Satisfiable(5..10))),
+            ("bytes=5-", Some(Satisfiable(5..10))),
+            ("bytes=-3", Some(Satisfiable(7..10))),
+            ("bytes=-20", Some(Satisfiable(0..10))),
+            ("bytes=10-", Some(Unsatisfiable)),
+            ("bytes=12-15", Some(Unsatisfiable)),
+            ("bytes=-0", Some(Unsatisfiable)),
+            ("bytes=4-2", None),
+            ("bytes=0-1,3-4", None),
+            ("bytes=-", None),
+            ("bytes=1", None),
+            ("bytes=a-b", None),
+            ("items=0-1", None),
+        ] {
+            assert_eq!(parse_range(header, 10), expected, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn appended_query_is_merged() {
+        let params = BTreeMap::from([
+            ("utm_medium".to_string(), "card".to_string()),
+            ("utm_source".to_string(), "shim".to_string()),
+        ]);
+        for (url, expected) in [
+            ("https://example.com", "https://example.com?utm_medium=card&utm_source=shim"),
+            (
+                "https://example.com/?a=1#top",
+                "https://example.com/?a=1&utm_medium=card&utm_source=shim#top",
+            ),
+            (
+                "https://example.com/?utm_source=card",
+                "https://example.com/?utm_source=card&utm_medium=card",
+            ),
+            (
+                "https://example.com/?utm_%73ource=x&utm_medium=y",
+                "https://example.com/?utm_%73ource=x&utm_medium=y",
+            ),
+            (
+                "https://example.com/#?utm_source=x",
+                "https://example.com/?utm_medium=card&utm_source=shim#?utm_source=x",
+            ),
+        ] {
+            assert_eq!(merge_query(url, &params), expected);
+        }
+    }
+
+    #[test]
+    fn alias_keys_are_normalized() {
+        let mut aliased = config("case_insensitive_paths = true\n[aliases]\n\"/Go/\" = \"/some/card/\"");
+        aliased.normalize_aliases().unwrap();
+        assert_eq!(aliased.aliases.get("go").map(String::as_str), Some("some/card"));
+
+        let mut aliased = config("trailing_slash = \"strict\"\n[aliases]\n\"/go/\" = \"a\"\n\"/go\" = \"b\"");
+        aliased.normalize_aliases().unwrap();
+        assert_eq!(aliased.aliases.get("go/").map(String::as_str), Some("a"));
+        assert_eq!(aliased.aliases.get("go").map(String::as_str), Some("b"));
+
+        let mut aliased = config("[aliases]\n\"/go\" = \"a\"\n\"go/\" = \"b\"");
+        assert!(aliased.normalize_aliases().is_err());
+    }
+
+    #[test]
+    fn signed_urls() {
+        let sign =
+            |message: &str| URL_SAFE_NO_PAD.encode(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"key"), message.as_bytes()));
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let sig = sign("some/path");
+        assert!(is_signed("key", "some/path", Some(&format!("sig={sig}")), now));
+        assert!(is_signed("key", "some/path", Some(&format!("a=1&sig={sig}&b=2")), now));
+        assert!(!is_signed("key", "some/other", Some(&format!("sig={sig}")), now));
+        assert!(!is_signed("other key", "some/path", Some(&format!("sig={sig}")), now));
+        assert!(!is_signed("key", "some/path", Some("sig=not base64"), now));
+        assert!(!is_signed("key", "some/path", Some("signature=x"), now));
+        assert!(!is_signed("key", "some/path", Some(""), now));
+        assert!(!is_signed("key", "some/path", None, now));
+
+        let sig = sign("some/path?exp=1000");
+        assert!(is_signed("key", "some/path", Some(&format!("exp=1000&sig={sig}")), now));
+        assert!(!is_signed(
+            "key",
+            "some/path",
+            Some(&format!("exp=1000&sig={sig}")),
+            now + Duration::from_secs(1)
+        ));
+        // the expiry is covered by the signature, so it can't be pushed back
+        assert!(!is_signed("key", "some/path", Some(&format!("exp=2000&sig={sig}")), now));
+        assert!(!is_signed("key", "some/path", Some(&format!("sig={sig}")), now));
+        assert!(!is_signed("key", "some/path", Some(&format!("exp=soon&sig={sig}")), now));
+
+        assert_eq!(without_signature("a=1&sig=x&exp=5&b=2"), "a=1&b=2");
+    }
 }
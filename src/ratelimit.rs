@@ -0,0 +1,99 @@
+// See license info in LICENSE file
+
+use std::{
+    net::IpAddr,
+    num::{NonZeroU32, NonZeroU64},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use moka::future::Cache;
+
+use crate::{listen::ClientIp, AppState};
+
+/// Most clients tracked at once. Past this the least recently seen are
+/// forgotten and start over with a full bucket
+const MAX_TRACKED_CLIENTS: u64 = 100_000;
+
+/// Paths that have to keep answering however busy a client is
+const EXEMPT_PATHS: [&str; 3] = ["/_/health", "/_/ready", "/_/metrics"];
+
+/// Token bucket per client ip, refilling `limit` tokens every `window`
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Cache<IpAddr, Arc<Mutex<Bucket>>>,
+    capacity: f64,
+    per_second: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: NonZeroU32, window_seconds: NonZeroU64) -> Self {
+        let window = Duration::from_secs(window_seconds.get());
+        Self {
+            // an idle bucket has refilled by the time it's evicted
+            buckets: Cache::builder()
+                .max_capacity(MAX_TRACKED_CLIENTS)
+                .time_to_idle(window)
+                .build(),
+            capacity: limit.get().into(),
+            per_second: f64::from(limit.get()) / window.as_secs_f64(),
+        }
+    }
+
+    /// Takes a token for the client, or says how long until one is available
+    async fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let bucket = self
+            .buckets
+            .get_with(ip, async {
+                Arc::new(Mutex::new(Bucket {
+                    tokens: self.capacity,
+                    updated: Instant::now(),
+                }))
+            })
+            .await;
+        let mut bucket = bucket.lock().unwrap();
+        let now = Instant::now();
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.per_second).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+}
+
+pub async fn limit_requests(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let (Some(limiter), Some(ClientIp(ip))) = (&state.limiter, request.extensions().get::<ClientIp>().copied()) else {
+        return next.run(request).await;
+    };
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    match limiter.acquire(ip).await {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            counter!("shim_rate_limited_total").increment(1);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", wait.as_secs_f64().ceil().to_string())],
+                "Too Many Requests",
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,457 @@
+// See license info in LICENSE file
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bb8::{Builder, ManageConnection, Pool, PooledConnection, RunError};
+use eyre::WrapErr;
+use redis::{
+    aio::{self, ConnectionLike, PubSub},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    sentinel::{Sentinel, SentinelNodeConnectionInfo},
+    Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, Pipeline, RedisError, RedisFuture, TlsCertificates,
+    TlsMode, Value,
+};
+
+use crate::Config;
+
+/// Schemes marking `database_url` as a comma separated list of cluster nodes
+const CLUSTER_SCHEMES: [(&str, &str); 2] = [("redis+cluster://", "redis://"), ("rediss+cluster://", "rediss://")];
+
+/// Checks the redis urls in the config can be parsed, without connecting to
+/// anything. Errors leave the urls out since they can hold passwords
+pub fn validate_urls(config: &Config) -> eyre::Result<()> {
+    let url = config.database_url.as_str();
+    if url.is_empty() {
+        eyre::bail!("database_url must be set");
+    }
+    if config.sentinel_master.is_some() {
+        url.into_connection_info().wrap_err("database_url isn't a valid redis url")?;
+        if config.sentinel_addresses.is_empty() {
+            eyre::bail!("sentinel_master needs at least one of sentinel_addresses");
+        }
+        for (i, address) in config.sentinel_addresses.iter().enumerate() {
+            address
+                .as_str()
+                .into_connection_info()
+                .wrap_err_with(|| format!("sentinel_addresses[{i}] isn't a valid redis url"))?;
+        }
+        return Ok(());
+    }
+    match CLUSTER_SCHEMES
+        .iter()
+        .find_map(|(cluster, node)| Some((url.strip_prefix(cluster)?, node)))
+    {
+        Some((nodes, scheme)) => {
+            for (i, node) in nodes.split(',').enumerate() {
+                format!("{scheme}{node}")
+                    .into_connection_info()
+                    .wrap_err_with(|| format!("node {} of database_url isn't a valid host and port", i + 1))?;
+            }
+        }
+        None => {
+            url.into_connection_info().wrap_err("database_url isn't a valid redis url")?;
+        }
+    }
+    Ok(())
+}
+
+/// Where entries live, along with how long any one command may take
+#[derive(Clone)]
+pub struct Store {
+    backend: Backend,
+    timeout: Duration,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Single(Pool<SingleManager>),
+    Sentinel(Pool<SentinelManager>),
+    Cluster {
+        /// Nodes from `database_url`, all sharing the same credentials
+        seeds: Vec<ConnectionInfo>,
+        /// Seed the last subscription went to
+        pubsub_seed: Arc<AtomicUsize>,
+        connection: ClusterConnection,
+    },
+}
+
+impl Store {
+    pub async fn connect(config: &Config) -> eyre::Result<Self> {
+        Ok(Self {
+            backend: Backend::connect(config).await?,
+            timeout: Duration::from_millis(config.redis_timeout_ms.get()),
+        })
+    }
+
+    pub async fn get(&self) -> eyre::Result<StoreConnection<'_>> {
+        let inner = match &self.backend {
+            Backend::Single(pool) => Inner::Single(pool.get().await?),
+            Backend::Sentinel(pool) => Inner::Sentinel(pool.get().await?),
+            Backend::Cluster { connection, .. } => Inner::Cluster(connection.clone()),
+        };
+        Ok(StoreConnection {
+            inner,
+            timeout: self.timeout,
+        })
+    }
+
+    /// Connections to every primary, since a scan only covers one node
+    pub async fn primaries(&self) -> eyre::Result<Vec<StoreConnection<'_>>> {
+        let Backend::Cluster { seeds, connection, .. } = &self.backend else {
+            return Ok(vec![self.get().await?]);
+        };
+        let slots: Vec<Vec<Value>> = redis::cmd("CLUSTER")
+            .arg("SLOTS")
+            .query_async(&mut connection.clone())
+            .await?;
+        let mut addrs = Vec::new();
+        for slot in &slots {
+            // each range is [start, end, primary, replicas...]
+            let Some(Value::Bulk(node)) = slot.get(2) else {
+                continue;
+            };
+            let [ip, port, ..] = node.as_slice() else {
+                continue;
+            };
+            let addr = (redis::from_redis_value::<String>(ip)?, redis::from_redis_value::<u16>(port)?);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        let mut primaries = Vec::with_capacity(addrs.len());
+        for (host, port) in addrs {
+            let seed = &seeds[0];
+            let addr = match &seed.addr {
+                ConnectionAddr::TcpTls {
+                    insecure, tls_params, ..
+                } => ConnectionAddr::TcpTls {
+                    host,
+                    port,
+                    insecure: *insecure,
+                    tls_params: tls_params.clone(),
+                },
+                _ => ConnectionAddr::Tcp(host, port),
+            };
+            let info = ConnectionInfo {
+                addr,
+                redis: seed.redis.clone(),
+            };
+            let client = redis::Client::open(info)?;
+            primaries.push(StoreConnection {
+                inner: Inner::Node(Tracked::new(client.get_async_connection().await?)),
+                timeout: self.timeout,
+            });
+        }
+        Ok(primaries)
+    }
+
+    /// Opens a connection for subscribing. publishes are broadcast to every
+    /// node of a cluster, so any seed node will do. it starts from the one
+    /// used last and moves on past any that can't be reached, so a dead node
+    /// doesn't stop invalidations
+    pub async fn pubsub(&self) -> eyre::Result<PubSub> {
+        Ok(match &self.backend {
+            Backend::Single(pool) => pool.dedicated_connection().await?.conn.into_pubsub(),
+            Backend::Sentinel(pool) => pool.dedicated_connection().await?.conn.into_pubsub(),
+            Backend::Cluster { seeds, pubsub_seed, .. } => {
+                let start = pubsub_seed.load(Ordering::Relaxed);
+                let mut last_err = None;
+                for index in (start..seeds.len()).chain(0..start) {
+                    let connect = async { redis::Client::open(seeds[index].clone())?.get_async_connection().await };
+                    match connect.await {
+                        Ok(connection) => {
+                            pubsub_seed.store(index, Ordering::Relaxed);
+                            return Ok(connection.into_pubsub());
+                        }
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                return Err(last_err.expect("there's always a seed").into());
+            }
+        })
+    }
+}
+
+impl Backend {
+    async fn connect(config: &Config) -> eyre::Result<Self> {
+        let tls = Tls {
+            insecure: config.redis_tls_insecure,
+            root_cert: config.redis_ca_file.as_ref().map(std::fs::read).transpose()?,
+        };
+        let url = config.database_url.as_str();
+
+        if let Some(master) = &config.sentinel_master {
+            let info = tls.apply(url.into_connection_info()?)?;
+            let manager = SentinelManager {
+                sentinels: config
+                    .sentinel_addresses
+                    .iter()
+                    .map(|v| tls.apply(v.as_str().into_connection_info()?))
+                    .collect::<eyre::Result<_>>()?,
+                master: master.clone(),
+                node: SentinelNodeConnectionInfo {
+                    // the role probe during discovery can't be given our ca, so it
+                    // skips verification and the real connection checks instead
+                    tls_mode: match info.addr {
+                        ConnectionAddr::TcpTls { insecure, .. } if insecure || tls.root_cert.is_some() => Some(TlsMode::Insecure),
+                        ConnectionAddr::TcpTls { .. } => Some(TlsMode::Secure),
+                        _ => None,
+                    },
+                    redis_connection_info: Some(info.redis),
+                },
+                tls,
+            };
+            return Ok(Self::Sentinel(pool_builder(config).build(manager).await?));
+        }
+
+        let Some((nodes, scheme)) = CLUSTER_SCHEMES
+            .iter()
+            .find_map(|(cluster, node)| Some((url.strip_prefix(cluster)?, node)))
+        else {
+            let manager = SingleManager {
+                client: redis::Client::open(tls.apply(url.into_connection_info()?)?)?,
+            };
+            return Ok(Self::Single(pool_builder(config).build(manager).await?));
+        };
+        let nodes = nodes
+            .split(',')
+            .map(|v| tls.apply(format!("{scheme}{v}").into_connection_info()?))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let seeds = nodes.clone();
+        let mut builder = ClusterClient::builder(nodes);
+        // the cluster client only takes certificates through the builder, and
+        // they force verification back on
+        if let (Some(root_cert), false) = (&tls.root_cert, tls.insecure) {
+            builder = builder.certs(TlsCertificates {
+                client_tls: None,
+                root_cert: Some(root_cert.clone()),
+            });
+        }
+        let connection = builder.build()?.get_async_connection().await?;
+        Ok(Self::Cluster {
+            seeds,
+            pubsub_seed: Default::default(),
+            connection,
+        })
+    }
+}
+
+/// Whether an error comes from redis being unreachable or unable to serve
+/// right now, rather than from a bug or bad data
+pub fn is_unavailable(err: &eyre::Report) -> bool {
+    err.chain().any(|err| {
+        if let Some(err) = err.downcast_ref::<RedisError>() {
+            return err.is_io_error()
+                || matches!(
+                    err.kind(),
+                    ErrorKind::BusyLoadingError
+                        | ErrorKind::TryAgain
+                        | ErrorKind::ClusterDown
+                        | ErrorKind::MasterDown
+                        | ErrorKind::MasterNameNotFoundBySentinel
+                );
+        }
+        matches!(err.downcast_ref::<RunError<RedisError>>(), Some(RunError::TimedOut))
+    })
+}
+
+fn pool_builder<M: ManageConnection>(config: &Config) -> Builder<M> {
+    Pool::builder()
+        .max_size(config.pool_max_size.get())
+        .min_idle(config.pool_min_idle)
+        .connection_timeout(Duration::from_secs(config.pool_timeout_seconds.get()))
+}
+
+/// A plain connection, which can't be reused once a command on it was cut
+/// short since the late reply would be read as the answer to the next one
+pub struct Tracked {
+    conn: aio::Connection,
+    abandoned: bool,
+}
+
+impl Tracked {
+    fn new(conn: aio::Connection) -> Self {
+        Self { conn, abandoned: false }
+    }
+
+    fn parts(&mut self) -> (&mut (dyn ConnectionLike + Send), Option<&mut bool>) {
+        (&mut self.conn, Some(&mut self.abandoned))
+    }
+}
+
+pub struct SingleManager {
+    client: redis::Client,
+}
+
+#[async_trait]
+impl ManageConnection for SingleManager {
+    type Connection = Tracked;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        Ok(Tracked::new(self.client.get_async_connection().await?))
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        redis::cmd("PING").query_async(&mut conn.conn).await
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.abandoned
+    }
+}
+
+/// Connects to whichever node the sentinels currently agree is the master
+pub struct SentinelManager {
+    sentinels: Vec<ConnectionInfo>,
+    master: String,
+    node: SentinelNodeConnectionInfo,
+    tls: Tls,
+}
+
+#[async_trait]
+impl ManageConnection for SentinelManager {
+    type Connection = Tracked;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        // a fresh sentinel each time, as its cached connections don't survive
+        // the sentinel going away
+        let client = Sentinel::build(self.sentinels.clone())?
+            .async_master_for(&self.master, Some(&self.node))
+            .await?;
+        let mut info = client.get_connection_info().clone();
+        if let ConnectionAddr::TcpTls { insecure, .. } = &mut info.addr {
+            *insecure = false;
+        }
+        let info = self
+            .tls
+            .apply(info)
+            .map_err(|err| RedisError::from((ErrorKind::InvalidClientConfig, "invalid tls settings", err.to_string())))?;
+        Ok(Tracked::new(redis::Client::open(info)?.get_async_connection().await?))
+    }
+
+    /// Rejects connections to a demoted master so the pool follows a failover
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        let role: Vec<Value> = redis::cmd("ROLE").query_async(&mut conn.conn).await?;
+        match role.first().map(redis::from_redis_value::<String>) {
+            Some(Ok(v)) if v == "master" => Ok(()),
+            _ => Err((ErrorKind::ResponseError, "no longer the master").into()),
+        }
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.abandoned
+    }
+}
+
+/// Settings for every `rediss://` connection
+struct Tls {
+    insecure: bool,
+    root_cert: Option<Vec<u8>>,
+}
+
+impl Tls {
+    fn apply(&self, mut info: ConnectionInfo) -> eyre::Result<ConnectionInfo> {
+        let ConnectionAddr::TcpTls { insecure, .. } = &mut info.addr else {
+            return Ok(info);
+        };
+        *insecure |= self.insecure;
+        let Some(root_cert) = &self.root_cert else {
+            return Ok(info);
+        };
+        let client = redis::Client::build_with_tls(info, TlsCertificates {
+            client_tls: None,
+            root_cert: Some(root_cert.clone()),
+        })?;
+        Ok(client.get_connection_info().clone())
+    }
+}
+
+pub struct StoreConnection<'a> {
+    inner: Inner<'a>,
+    timeout: Duration,
+}
+
+impl StoreConnection<'_> {
+    /// Whether commands get spread over a cluster, where keys from different
+    /// slots can't be used together
+    pub fn is_cluster(&self) -> bool {
+        matches!(self.inner, Inner::Cluster(_))
+    }
+}
+
+enum Inner<'a> {
+    Single(PooledConnection<'a, SingleManager>),
+    Sentinel(PooledConnection<'a, SentinelManager>),
+    // multiplexed, so an abandoned command doesn't affect the next
+    Cluster(ClusterConnection),
+    Node(Tracked),
+}
+
+impl Inner<'_> {
+    /// The connection to send commands on, and the flag to set if one of them
+    /// gets abandoned
+    fn parts(&mut self) -> (&mut (dyn ConnectionLike + Send), Option<&mut bool>) {
+        match self {
+            Self::Single(v) => v.parts(),
+            Self::Sentinel(v) => v.parts(),
+            Self::Cluster(v) => (v, None),
+            Self::Node(v) => v.parts(),
+        }
+    }
+}
+
+impl ConnectionLike for StoreConnection<'_> {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let (conn, abandoned) = self.inner.parts();
+        bounded(self.timeout, abandoned, conn.req_packed_command(cmd))
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        let (conn, abandoned) = self.inner.parts();
+        bounded(self.timeout, abandoned, conn.req_packed_commands(cmd, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        match &self.inner {
+            Inner::Single(v) => v.conn.get_db(),
+            Inner::Sentinel(v) => v.conn.get_db(),
+            Inner::Cluster(v) => v.get_db(),
+            Inner::Node(v) => v.conn.get_db(),
+        }
+    }
+}
+
+/// Fails a command that takes longer than `timeout`, marking its connection
+/// as abandoned. The mark stays if the caller drops the command partway
+/// through too, since the reply would go to whoever uses the connection next
+fn bounded<'a, T: Send + 'a>(
+    timeout: Duration,
+    mut abandoned: Option<&'a mut bool>,
+    command: RedisFuture<'a, T>,
+) -> RedisFuture<'a, T> {
+    Box::pin(async move {
+        if abandoned.as_deref() == Some(&true) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection abandoned after a timeout").into());
+        }
+        if let Some(abandoned) = abandoned.as_deref_mut() {
+            *abandoned = true;
+        }
+        let result = tokio::time::timeout(timeout, command).await;
+        if let (Ok(_), Some(abandoned)) = (&result, abandoned) {
+            *abandoned = false;
+        }
+        result.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "redis command timed out").into()))
+    })
+}
@@ -0,0 +1,167 @@
+// See license info in LICENSE file
+
+use std::{future::Future, time::Instant};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use redis::RedisResult;
+use serde::Deserialize;
+use tower_http::request_id::RequestId;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{listen::ClientIp, AppState, Config};
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+/// Keeps the trace exporter alive, flushing any pending spans when dropped
+pub struct TracingGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.provider {
+            if let Err(err) = provider.shutdown() {
+                tracing::error!("failed to shut down trace exporter: {err:?}");
+            }
+        }
+    }
+}
+
+pub fn init_tracing(config: &Config) -> eyre::Result<TracingGuard> {
+    let fmt_layer = match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+
+    #[cfg(feature = "otel")]
+    let (registry, provider) = {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let provider = config.otlp_endpoint.as_deref().map(build_tracer_provider).transpose()?;
+        let layer = provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("site-shim")));
+        (registry.with(layer), provider)
+    };
+
+    registry.init();
+
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("otlp_endpoint is set but site-shim was built without the otel feature, not exporting traces");
+    }
+
+    Ok(TracingGuard {
+        #[cfg(feature = "otel")]
+        provider,
+    })
+}
+
+#[cfg(feature = "otel")]
+fn build_tracer_provider(endpoint: &str) -> eyre::Result<opentelemetry_sdk::trace::TracerProvider> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", "site-shim")]))
+        .build())
+}
+
+/// Bucket boundaries in seconds, fine grained at the low end where cache hits
+/// land and coarser out where redis misses do
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+pub fn install_recorder() -> eyre::Result<PrometheusHandle> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("shim_request_duration_seconds".to_string()),
+            REQUEST_DURATION_BUCKETS,
+        )?
+        .install_recorder()?)
+}
+
+/// Counts every response by status code, and wraps the request in a span so
+/// anything logged while handling it carries the request id, method, and path
+pub async fn track_requests(State(state): State<AppState>, request: Request<Body>, next: Next<Body>) -> Response {
+    let span = info_span!(
+        "request",
+        id = request
+            .extensions()
+            .get::<RequestId>()
+            .and_then(|v| v.header_value().to_str().ok())
+            .unwrap_or("none"),
+        client = request.extensions().get::<ClientIp>().map(|v| v.0.to_string()).as_deref().unwrap_or("none"),
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let start = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let latency = start.elapsed();
+    let cache_status = match response.headers().get("X-Cache-Status").and_then(|v| v.to_str().ok()) {
+        Some("hit") => "hit",
+        Some("miss") => "miss",
+        _ => "none",
+    };
+    counter!("shim_http_requests_total", "status" => response.status().as_u16().to_string()).increment(1);
+    histogram!("shim_request_duration_seconds", "cache_status" => cache_status).record(latency.as_secs_f64());
+
+    if state.config.load().access_log {
+        span.in_scope(|| {
+            info!(
+                status = response.status().as_u16(),
+                latency_ms = latency.as_secs_f64() * 1000.0,
+                cache_status,
+                "served request"
+            )
+        });
+    }
+
+    response
+}
+
+/// Runs a redis command in its own span, recording how long it took and
+/// whether it failed
+pub async fn redis_call<T>(command: &'static str, call: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+    let start = Instant::now();
+    let result = call.instrument(info_span!("redis", command)).await;
+    histogram!("shim_redis_command_duration_seconds", "command" => command).record(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        counter!("shim_redis_errors_total", "command" => command).increment(1);
+    }
+    result
+}
+
+pub async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    match state.metrics {
+        Some(handle) => (StatusCode::OK, handle.render()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -1,9 +1,13 @@
 # See license info in LICENSE file
 
+import base64
+import hashlib
+import hmac
 import os
 import json
 import redis
 import sys
+import time
 import typing
 
 
@@ -17,7 +21,7 @@ def listdir(root: str, acc: str = '') -> typing.Iterator[str]:
 
 
 if len(sys.argv) == 1:
-    print(f'usage: {sys.argv[0]} <sync_assets|sync_cards>')
+    print(f'usage: {sys.argv[0]} <sync_assets|sync_cards|sign>')
     exit(1)
 
 if sys.argv[1] == 'sync_assets' and len(sys.argv) == 2:
@@ -28,12 +32,58 @@ if sys.argv[1] == 'sync_cards' and len(sys.argv) == 2:
     print(f'usage: {sys.argv[0]} sync_cards <directory>')
     exit(1)
 
+if sys.argv[1] == 'sign':
+    if len(sys.argv) == 2:
+        print(f'usage: {sys.argv[0]} sign <path> [valid_for_seconds]')
+        exit(1)
+    signing_key = os.environ.get('URL_SIGNING_KEY')
+    if signing_key is None:
+        print('missing environment variable URL_SIGNING_KEY')
+        exit(1)
+    path = sys.argv[2].lstrip('/')
+    message = path
+    query = ''
+    if len(sys.argv) > 3:
+        query = f'exp={int(time.time()) + int(sys.argv[3])}&'
+        message = f'{path}?{query[:-1]}'
+    sig = hmac.new(signing_key.encode('utf8'), message.encode('utf8'),
+                   hashlib.sha256).digest()
+    print(f'/{path}?{query}sig={base64.urlsafe_b64encode(sig).decode().rstrip("=")}')
+    exit(0)
+
 redis_url = os.environ.get('REDIS_URL')
 if redis_url is None:
     print('missing environment variable REDIS_URL')
     exit(1)
 
 redis = redis.Redis.from_url(redis_url)
+key_prefix = os.environ.get('KEY_PREFIX', '')
+invalidations_channel = os.environ.get('INVALIDATIONS_CHANNEL', 'invalidations')
+# with host_keys on, everything for a site sits under its host
+site = os.environ.get('SITE', '').lower()
+if site:
+    key_prefix += f'{site}:'
+asset_before, asset_after = os.environ.get(
+    'ASSET_KEY_TEMPLATE', 'asset:{path}').split('{path}')
+card_before, card_after = os.environ.get(
+    'CARD_KEY_TEMPLATE', 'card:{path}').split('{path}')
+
+
+def asset_key(path):
+    return f'{key_prefix}{asset_before}{path}{asset_after}'
+
+
+def card_key(path):
+    return f'{key_prefix}{card_before}{path}{card_after}'
+
+
+def invalidation(path):
+    return f'{site}:{path}' if site else path
+
+
+def key_path(key, before, after):
+    key = key.decode('utf8')
+    return key[len(key_prefix) + len(before):len(key) - len(after)]
 
 mimetypes = {
     'html': b'text/html',
@@ -53,7 +103,8 @@ if sys.argv[1] == 'sync_assets':
         local.append((path[:-11] if path.endswith('index.html')
                      else path, f'{directory}/{path}'))
 
-    remote = [key[6:].decode('utf8') for key in redis.scan_iter('asset:*')]
+    remote = [key_path(key, asset_before, asset_after)
+              for key in redis.scan_iter(asset_key('*'))]
 
     for item in local:
         try:
@@ -66,13 +117,16 @@ if sys.argv[1] == 'sync_assets':
                 '.')[-1], b'text/plain') + b';'
             while (data := f.read()) != b'':
                 buffer += data
-            redis.set(f'asset:{item[0]}', buffer)
-        redis.publish('invalidations', item[0])
-        print(f'uploaded {item[1]} to asset:{item[0]}')
+            redis.set(asset_key(item[0]), buffer)
+        redis.set(f'{key_prefix}modified:{item[0]}',
+                  int(os.path.getmtime(item[1])))
+        redis.publish(invalidations_channel, invalidation(item[0]))
+        print(f'uploaded {item[1]} to {asset_key(item[0])}')
 
     for item in remote:
-        key = f'asset:{item}'
+        key = asset_key(item)
         redis.expire(key, 60 * 60 * 24)
+        redis.expire(f'{key_prefix}modified:{item}', 60 * 60 * 24)
         print(f'expired {key}')
 
 elif sys.argv[1] == 'sync_cards':
@@ -80,7 +134,8 @@ elif sys.argv[1] == 'sync_cards':
 
     local = [(path.split('/')[-1].split('.')[0], f'{directory}/{path}')
              for path in listdir(directory)]
-    remote = [key[5:].decode('utf8') for key in redis.scan_iter('card:*')]
+    remote = [key_path(key, card_before, card_after)
+              for key in redis.scan_iter(card_key('*'))]
 
     for item in local:
         try:
@@ -90,11 +145,11 @@ elif sys.argv[1] == 'sync_cards':
 
         with open(item[1], 'rb') as f:
             buffer = json.dumps(json.load(f))
-            redis.set(f'card:{item[0]}', buffer)
-        redis.publish('invalidations', item[0])
+            redis.set(card_key(item[0]), buffer)
+        redis.publish(invalidations_channel, invalidation(item[0]))
         print(f'updated card {item[0]} from {item[1]}')
 
     for item in remote:
-        key = f'card:{item}'
+        key = card_key(item)
         redis.expire(key, 60 * 60 * 24)
         print(f'expired {key}')